@@ -0,0 +1,259 @@
+//! Battery voltage and state of charge.
+//!
+//! In the buck configuration the battery supplies `VBAT_HIGH`, in the boost
+//! configuration it supplies `VBAT_LOW`. [`Battery`] measures the right input
+//! with the GPADC and maps the voltage to a state of charge with a
+//! [`DischargeCurve`].
+//!
+//! A battery above 3.6 V, like a lithium ion cell, exceeds both the supply
+//! range of the DA14531 and the input range of the GPADC. It is measured at an
+//! external ADC pin through a resistive divider, see
+//! [`Battery::set_external_input`].
+
+use crate::{
+    crg_top::CrgTop,
+    gpadc::{
+        config::{
+            AdcConfig, AdcInputExternal, AdcInputVbatHigh, AdcInputVbatLow, Attenuation, Averaging,
+            Chopper, SampleTime,
+        },
+        GpAdc,
+    },
+    hal::adc::Channel,
+    pac::GPADC,
+};
+
+/// Battery voltage in mV and state of charge in percent, ordered by
+/// descending voltage
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DischargeCurve {
+    points: &'static [(u16, u8)],
+}
+
+impl DischargeCurve {
+    /// # Panics
+    ///
+    /// Panics if `points` is empty, or if the voltage does not strictly
+    /// decrease or the percentage increases from one point to the next.
+    pub const fn new(points: &'static [(u16, u8)]) -> Self {
+        assert!(!points.is_empty());
+
+        let mut i = 1;
+        while i < points.len() {
+            assert!(points[i].0 < points[i - 1].0);
+            assert!(points[i].1 <= points[i - 1].1);
+            i += 1;
+        }
+
+        Self { points }
+    }
+
+    /// State of charge at `millivolts`, linearly interpolated between the
+    /// points of the curve
+    pub fn percent(&self, millivolts: u16) -> u8 {
+        let (first_mv, first_percent) = self.points[0];
+        if millivolts >= first_mv {
+            return first_percent;
+        }
+
+        for pair in self.points.windows(2) {
+            let (high_mv, high_percent) = pair[0];
+            let (low_mv, low_percent) = pair[1];
+
+            if millivolts >= low_mv {
+                let span = (high_percent - low_percent) as u32;
+                let above = (millivolts - low_mv) as u32;
+
+                return low_percent + (above * span / (high_mv - low_mv) as u32) as u8;
+            }
+        }
+
+        self.points[self.points.len() - 1].1
+    }
+}
+
+/// Lithium coin cell, nominal 3 V
+pub const CR2032: DischargeCurve = DischargeCurve::new(&[
+    (3000, 100),
+    (2900, 90),
+    (2800, 70),
+    (2700, 40),
+    (2600, 20),
+    (2500, 10),
+    (2000, 0),
+]);
+
+/// Two alkaline AAA cells in series, nominal 3 V
+pub const ALKALINE_AAA_X2: DischargeCurve = DischargeCurve::new(&[
+    (3200, 100),
+    (3000, 90),
+    (2800, 70),
+    (2600, 45),
+    (2400, 25),
+    (2200, 10),
+    (2000, 0),
+]);
+
+/// Single alkaline AAA cell, nominal 1.5 V
+pub const ALKALINE_AAA: DischargeCurve = DischargeCurve::new(&[
+    (1600, 100),
+    (1500, 90),
+    (1400, 70),
+    (1300, 45),
+    (1200, 25),
+    (1100, 10),
+    (1000, 0),
+]);
+
+/// Lithium ion cell, nominal 3.7 V
+///
+/// The GPADC measures up to 3.6 V, the cell has to be measured through an
+/// external divider with [`Battery::set_external_input`].
+pub const LI_ION: DischargeCurve = DischargeCurve::new(&[
+    (4200, 100),
+    (4100, 90),
+    (4000, 80),
+    (3900, 65),
+    (3800, 50),
+    (3700, 30),
+    (3600, 15),
+    (3500, 5),
+    (3300, 0),
+]);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BatteryType {
+    Cr2032,
+    AlkalineAaaX2,
+    AlkalineAaa,
+    LiIon,
+    Custom(DischargeCurve),
+}
+
+impl BatteryType {
+    pub fn curve(&self) -> DischargeCurve {
+        match self {
+            BatteryType::Cr2032 => CR2032,
+            BatteryType::AlkalineAaaX2 => ALKALINE_AAA_X2,
+            BatteryType::AlkalineAaa => ALKALINE_AAA,
+            BatteryType::LiIon => LI_ION,
+            BatteryType::Custom(curve) => *curve,
+        }
+    }
+}
+
+pub struct Battery {
+    battery_type: BatteryType,
+    /// Battery supplies `VBAT_LOW` through the boost converter
+    boost: bool,
+    /// Load current in uA during the measurement
+    load_ua: u32,
+    /// Internal resistance of the battery in mOhm
+    resistance_mohm: u32,
+    /// External ADC pin, numerator and denominator of its divider ratio
+    external: Option<(u8, u16, u16)>,
+}
+
+impl Battery {
+    /// Measure a battery in the DC-DC configuration selected by the hardware
+    pub fn new(battery_type: BatteryType, crg_top: &CrgTop) -> Self {
+        Self {
+            battery_type,
+            boost: crg_top.boost_selected(),
+            load_ua: 0,
+            resistance_mohm: 0,
+            external: None,
+        }
+    }
+
+    /// Measure the battery at an external ADC pin behind a resistive divider
+    ///
+    /// The voltage at the pin is multiplied by `numerator / denominator`, for a
+    /// divider of `r_top` and `r_bottom` this is `(r_top + r_bottom) / r_bottom`.
+    /// The divider has to keep the pin below 3.6 V.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `denominator` is zero.
+    pub fn set_external_input<P>(mut self, _pin: &P, numerator: u16, denominator: u16) -> Self
+    where
+        P: Channel<GPADC, ID = u8> + AdcInputExternal,
+    {
+        assert!(denominator != 0);
+
+        self.external = Some((P::channel(), numerator, denominator));
+        self
+    }
+
+    /// Add the voltage drop of `load_ua` across the internal resistance of the
+    /// battery to every measurement
+    ///
+    /// The state of charge is then estimated from the open circuit voltage,
+    /// which does not dip while the radio is transmitting.
+    pub fn set_load_compensation(mut self, load_ua: u32, resistance_mohm: u32) -> Self {
+        self.load_ua = load_ua;
+        self.resistance_mohm = resistance_mohm;
+        self
+    }
+
+    pub fn battery_type(&self) -> BatteryType {
+        self.battery_type
+    }
+
+    /// Check whether the battery supplies the boost converter
+    pub fn is_boost(&self) -> bool {
+        self.boost
+    }
+
+    /// Measure the battery voltage in mV, compensated for the load
+    ///
+    /// Reinitializes the ADC, it has to be configured for other measurements
+    /// afterwards.
+    pub fn read_millivolts(&self, adc: &mut GpAdc) -> u16 {
+        let config = AdcConfig::default()
+            .set_chopper_mode(Chopper::On)
+            .set_averaging(Averaging::SamplesX4)
+            .set_sample_time(SampleTime::Cycles2X8);
+
+        // Each input uses the smallest attenuation which covers its voltage, as
+        // every step of attenuation costs resolution
+        let config = match self.external {
+            // The divider is chosen to use the full range of up to 3.6 V
+            Some((channel, _, _)) => {
+                let mut config = config.set_attenuation(Attenuation::X4);
+                config.channel_sel_pos = channel;
+                config
+            }
+            // The boost converter runs from a single cell of up to 1.65 V, x2
+            // covers up to 1.8 V
+            None if self.boost => config
+                .set_channel_pos(&AdcInputVbatLow)
+                .set_attenuation(Attenuation::X2),
+            // In buck mode VBAT_HIGH is supplied with up to 3.3 V, which needs
+            // the 3.6 V range of x4
+            None => config
+                .set_channel_pos(&AdcInputVbatHigh)
+                .set_attenuation(Attenuation::X4),
+        };
+
+        let sample = adc.measure(config);
+        let millivolts = adc.convert_to_millivolts(sample).max(0) as u32;
+        let millivolts = match self.external {
+            Some((_, numerator, denominator)) => millivolts * numerator as u32 / denominator as u32,
+            None => millivolts,
+        };
+        let drop = (self.load_ua as u64 * self.resistance_mohm as u64 / 1_000_000) as u32;
+
+        (millivolts + drop).min(u16::MAX as u32) as u16
+    }
+
+    /// Measure the state of charge in percent
+    pub fn read_percent(&self, adc: &mut GpAdc) -> u8 {
+        self.percent(self.read_millivolts(adc))
+    }
+
+    /// State of charge in percent at `millivolts`
+    pub fn percent(&self, millivolts: u16) -> u8 {
+        self.battery_type.curve().percent(millivolts)
+    }
+}
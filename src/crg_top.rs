@@ -1,4 +1,4 @@
-use crate::pac::CRG_TOP;
+use crate::{pac::CRG_TOP, time::Hertz};
 
 #[repr(u16)]
 pub enum PeripheralClock {
@@ -62,12 +62,55 @@ peripheral_clock_enable!(
 
 impl CrgTopExt for CRG_TOP {
     fn constrain(self) -> CrgTop {
-        CrgTop { crg_top: self }
+        CrgTop {
+            crg_top: self,
+            rcx_freq: RCX_FREQ,
+        }
     }
 }
 
+/// Typical frequency of the RCX oscillator
+///
+/// The actual frequency varies from part to part and with temperature, it is
+/// measured against XTAL32M by the calibration of the SDK.
+const RCX_FREQ: Hertz = Hertz::from_raw(15_000);
+
 pub struct CrgTop {
     crg_top: CRG_TOP,
+    rcx_freq: Hertz,
+}
+
+/// Frequencies of the clocks derived by CRG_TOP
+///
+/// Created by [`CrgTop::freeze`], drivers use it to compute their dividers.
+#[derive(Clone, Copy, Debug)]
+pub struct Clocks {
+    sys_clk: Hertz,
+    hclk: Hertz,
+    pclk: Hertz,
+    lp_clk: Hertz,
+}
+
+impl Clocks {
+    /// System clock, also used by the timers
+    pub fn sys_clk(&self) -> Hertz {
+        self.sys_clk
+    }
+
+    /// AHB and CPU clock
+    pub fn hclk(&self) -> Hertz {
+        self.hclk
+    }
+
+    /// APB clock
+    pub fn pclk(&self) -> Hertz {
+        self.pclk
+    }
+
+    /// Low power clock
+    pub fn lp_clk(&self) -> Hertz {
+        self.lp_clk
+    }
 }
 
 impl CrgTop {
@@ -111,6 +154,50 @@ impl CrgTop {
         });
     }
 
+    /// Set the measured frequency of the RCX oscillator
+    ///
+    /// Without it [`freeze`](Self::freeze) assumes the typical 15 kHz when RCX
+    /// is the low power clock.
+    pub fn set_rcx_frequency(&mut self, freq: Hertz) {
+        self.rcx_freq = freq;
+    }
+
+    /// Capture the current clock configuration
+    ///
+    /// The clock sources and AMBA dividers should not be changed afterwards, as
+    /// drivers configured with the returned frequencies would run off. RCX is
+    /// not measured, its frequency is the one given to
+    /// [`set_rcx_frequency`](Self::set_rcx_frequency), 15 kHz by default.
+    pub fn freeze(&self) -> Clocks {
+        let clk_ctrl = self.crg_top.clk_ctrl_reg.read();
+        let clk_amba = self.crg_top.clk_amba_reg.read();
+
+        let lp_clk = match clk_ctrl.lp_clk_sel().bits() {
+            // RC32K
+            0 => 32_000,
+            // RCX
+            1 => self.rcx_freq.raw(),
+            // XTAL32K
+            _ => 32_768,
+        };
+
+        // XTAL32M and RC32M are divided by two
+        let sys_clk = match clk_ctrl.sys_clk_sel().bits() {
+            0 | 1 => 16_000_000,
+            _ => lp_clk,
+        };
+
+        let hclk = sys_clk >> clk_amba.hclk_div().bits();
+        let pclk = hclk >> clk_amba.pclk_div().bits();
+
+        Clocks {
+            sys_clk: Hertz::from_raw(sys_clk),
+            hclk: Hertz::from_raw(hclk),
+            pclk: Hertz::from_raw(pclk),
+            lp_clk: Hertz::from_raw(lp_clk),
+        }
+    }
+
     #[inline]
     pub fn is_dbg_up(&self) -> bool {
         self.crg_top.sys_stat_reg.read().dbg_is_up().bit()
@@ -0,0 +1,141 @@
+//! Blocking delays based on the SysTick timer.
+//!
+//! SysTick counts down at the CPU clock (HCLK). Its reload value is only 24 bits
+//! wide, longer delays are split into several SysTick periods.
+
+use crate::{
+    cm::peripheral::{syst::SystClkSource, SYST},
+    crg_top::Clocks,
+    hal::blocking::delay::{DelayMs, DelayUs},
+};
+
+/// Largest SysTick period in clock cycles
+const MAX_PERIOD: u64 = 1 << 24;
+
+/// Clock cycles of `time` in units of `1 / per_second` s at `hclk`, rounded
+/// up so that a delay never falls short
+fn cycles(time: u32, per_second: u64, hclk: u32) -> u64 {
+    (time as u64 * hclk as u64 + per_second - 1) / per_second
+}
+
+/// SysTick used as delay provider
+pub struct Delay {
+    syst: SYST,
+    hclk: u32,
+}
+
+impl Delay {
+    /// Configure SysTick to count at the CPU clock as captured in `clocks`
+    pub fn new(mut syst: SYST, clocks: &Clocks) -> Self {
+        syst.disable_interrupt();
+        syst.disable_counter();
+        syst.set_clock_source(SystClkSource::Core);
+
+        Self {
+            syst,
+            hclk: clocks.hclk().raw(),
+        }
+    }
+
+    /// Release the SysTick timer
+    pub fn free(self) -> SYST {
+        self.syst
+    }
+
+    pub fn delay_ns(&mut self, ns: u32) {
+        self.delay_cycles(cycles(ns, 1_000_000_000, self.hclk));
+    }
+
+    pub fn delay_us(&mut self, us: u32) {
+        self.delay_cycles(cycles(us, 1_000_000, self.hclk));
+    }
+
+    pub fn delay_ms(&mut self, ms: u32) {
+        self.delay_cycles(cycles(ms, 1_000, self.hclk));
+    }
+
+    fn delay_cycles(&mut self, mut cycles: u64) {
+        while cycles > 0 {
+            // A reload value of zero would never wrap, a single cycle is
+            // stretched to two
+            let period = cycles.clamp(2, MAX_PERIOD);
+
+            self.syst.set_reload((period - 1) as u32);
+            self.syst.clear_current();
+            self.syst.enable_counter();
+
+            while !self.syst.has_wrapped() {}
+
+            self.syst.disable_counter();
+            cycles = cycles.saturating_sub(period);
+        }
+    }
+}
+
+macro_rules! delay_impls {
+    ($($t:ty),+) => {
+        $(
+            impl DelayMs<$t> for Delay {
+                fn delay_ms(&mut self, ms: $t) {
+                    Delay::delay_ms(self, ms as u32);
+                }
+            }
+
+            impl DelayUs<$t> for Delay {
+                fn delay_us(&mut self, us: $t) {
+                    Delay::delay_us(self, us as u32);
+                }
+            }
+        )+
+    };
+}
+
+delay_impls!(u8, u16, u32);
+
+impl embedded_hal_1::delay::DelayNs for Delay {
+    fn delay_ns(&mut self, ns: u32) {
+        Delay::delay_ns(self, ns);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        Delay::delay_us(self, us);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        Delay::delay_ms(self, ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HCLK: u32 = 2_000_000;
+
+    #[test]
+    fn cycles_round_up() {
+        // One cycle is 500 ns at 2 MHz
+        assert_eq!(cycles(0, 1_000_000_000, HCLK), 0);
+        assert_eq!(cycles(1, 1_000_000_000, HCLK), 1);
+        assert_eq!(cycles(500, 1_000_000_000, HCLK), 1);
+        assert_eq!(cycles(501, 1_000_000_000, HCLK), 2);
+
+        assert_eq!(cycles(1, 1_000_000, HCLK), 2);
+        assert_eq!(cycles(1, 1_000, HCLK), 2_000);
+
+        // Odd clocks which do not divide evenly
+        assert_eq!(cycles(1, 1_000_000, 1_500_000), 2);
+        assert_eq!(cycles(3, 1_000, 32_768), 99);
+    }
+
+    #[test]
+    fn units_agree() {
+        for time in 0..10_000 {
+            let ns = cycles(time * 1_000, 1_000_000_000, HCLK);
+            let us = cycles(time, 1_000_000, HCLK);
+
+            assert_eq!(ns, us);
+            assert!(us * 1_000_000 >= time as u64 * HCLK as u64);
+        }
+    }
+}
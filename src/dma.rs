@@ -0,0 +1,316 @@
+//! HAL interface to the DMA controller.
+//!
+//! The DMA controller provides four channels which are organized in two pairs.
+//! Each pair is connected to the request lines of one peripheral: the even
+//! channel serves the receive request and the odd channel the transmit request.
+//!
+//! The PAC does not describe the DMA registers, so they are accessed directly.
+//! See:
+//! * sdk/sdk/platform/driver/dma/dma.c
+
+use core::{cell::RefCell, ptr};
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    nvic::{Irq, Nvic},
+};
+
+const DMA_BASE: usize = 0x5000_3600;
+const DMA_CHANNEL_STRIDE: usize = 0x10;
+
+const DMA_REQ_MUX_REG: usize = 0x5000_3680;
+const DMA_INT_STATUS_REG: usize = 0x5000_3682;
+const DMA_CLEAR_INT_REG: usize = 0x5000_3684;
+
+const DMA_A_STARTL_REG: usize = 0x0;
+const DMA_A_STARTH_REG: usize = 0x2;
+const DMA_B_STARTL_REG: usize = 0x4;
+const DMA_B_STARTH_REG: usize = 0x6;
+const DMA_INT_REG: usize = 0x8;
+const DMA_LEN_REG: usize = 0xA;
+const DMA_CTRL_REG: usize = 0xC;
+const DMA_IDX_REG: usize = 0xE;
+
+const DMA_ON: u16 = 1 << 0;
+const DMA_BW_POS: u16 = 1;
+const DREQ_MODE: u16 = 1 << 3;
+const BINC: u16 = 1 << 4;
+const AINC: u16 = 1 << 5;
+const CIRCULAR: u16 = 1 << 6;
+
+static TAKEN: Mutex<RefCell<bool>> = Mutex::new(RefCell::new(false));
+
+/// Handlers of the four channels
+type Handlers = [Option<fn()>; 4];
+
+static DMA_HANDLERS: Mutex<RefCell<Handlers>> = Mutex::new(RefCell::new([None; 4]));
+
+/// Peripheral request lines which can be connected to a channel pair
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Trigger {
+    Spi = 0x0,
+    Uart = 0x1,
+    Uart2 = 0x2,
+    I2c = 0x3,
+    Adc = 0x4,
+    None = 0xF,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum BusWidth {
+    Byte = 0,
+    HalfWord = 1,
+    Word = 2,
+}
+
+/// Configuration of a single DMA channel
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChannelConfig {
+    pub(crate) src: u32,
+    pub(crate) dst: u32,
+    pub(crate) len: u16,
+    pub(crate) bus_width: BusWidth,
+    pub(crate) src_inc: bool,
+    pub(crate) dst_inc: bool,
+    pub(crate) circular: bool,
+    pub(crate) peripheral_request: bool,
+    pub(crate) irq_at: u16,
+}
+
+impl ChannelConfig {
+    /// Transfer `len` items of `bus_width` from `src` to `dst`
+    pub fn new(src: u32, dst: u32, len: u16, bus_width: BusWidth) -> Self {
+        assert!(len > 0);
+
+        Self {
+            src,
+            dst,
+            len,
+            bus_width,
+            src_inc: true,
+            dst_inc: true,
+            circular: false,
+            peripheral_request: false,
+            irq_at: len,
+        }
+    }
+
+    pub fn set_src_inc(mut self, src_inc: bool) -> Self {
+        self.src_inc = src_inc;
+        self
+    }
+
+    pub fn set_dst_inc(mut self, dst_inc: bool) -> Self {
+        self.dst_inc = dst_inc;
+        self
+    }
+
+    /// Restart from the beginning once `len` items have been transferred
+    pub fn set_circular(mut self, circular: bool) -> Self {
+        self.circular = circular;
+        self
+    }
+
+    /// Move one item per request of the peripheral connected to the channel pair
+    pub fn set_peripheral_request(mut self, peripheral_request: bool) -> Self {
+        self.peripheral_request = peripheral_request;
+        self
+    }
+
+    /// Raise the channel interrupt after `count` items, defaults to `len`
+    pub fn set_irq_at(mut self, count: u16) -> Self {
+        assert!(count > 0 && count <= self.len);
+        self.irq_at = count;
+        self
+    }
+}
+
+/// A single DMA channel
+pub struct Channel {
+    index: u8,
+}
+
+impl Channel {
+    /// Create a second handle to a channel, used from interrupt context by
+    /// drivers which own the channel
+    pub(crate) unsafe fn steal(index: u8) -> Self {
+        Self { index }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u16 {
+        (DMA_BASE + DMA_CHANNEL_STRIDE * self.index as usize + offset) as *mut u16
+    }
+
+    fn read(&self, offset: usize) -> u16 {
+        unsafe { ptr::read_volatile(self.reg(offset)) }
+    }
+
+    fn write(&self, offset: usize, value: u16) {
+        unsafe { ptr::write_volatile(self.reg(offset), value) }
+    }
+
+    /// Channel number (0-3)
+    #[inline]
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Configure the channel, it must not be running
+    pub fn configure(&mut self, config: ChannelConfig) {
+        self.stop();
+
+        self.write(DMA_A_STARTL_REG, config.src as u16);
+        self.write(DMA_A_STARTH_REG, (config.src >> 16) as u16);
+        self.write(DMA_B_STARTL_REG, config.dst as u16);
+        self.write(DMA_B_STARTH_REG, (config.dst >> 16) as u16);
+        self.write(DMA_LEN_REG, config.len - 1);
+        self.write(DMA_INT_REG, config.irq_at - 1);
+
+        let mut ctrl = (config.bus_width as u16) << DMA_BW_POS;
+        if config.peripheral_request {
+            ctrl |= DREQ_MODE;
+        }
+        if config.dst_inc {
+            ctrl |= BINC;
+        }
+        if config.src_inc {
+            ctrl |= AINC;
+        }
+        if config.circular {
+            ctrl |= CIRCULAR;
+        }
+
+        self.write(DMA_CTRL_REG, ctrl);
+    }
+
+    /// Move the channel interrupt to `count` items, also while the channel
+    /// is running
+    pub fn set_irq_at(&mut self, count: u16) {
+        assert!(count > 0 && count <= self.read(DMA_LEN_REG) + 1);
+        self.write(DMA_INT_REG, count - 1);
+    }
+
+    pub fn start(&mut self) {
+        self.write(DMA_CTRL_REG, self.read(DMA_CTRL_REG) | DMA_ON);
+    }
+
+    pub fn stop(&mut self) {
+        self.write(DMA_CTRL_REG, self.read(DMA_CTRL_REG) & !DMA_ON);
+        self.clear_interrupt();
+    }
+
+    /// Check whether the channel is still transferring
+    ///
+    /// A non-circular channel turns itself off after the last item.
+    pub fn is_busy(&self) -> bool {
+        self.read(DMA_CTRL_REG) & DMA_ON != 0
+    }
+
+    /// Number of items transferred since the channel has been started
+    pub fn transferred(&self) -> u16 {
+        self.read(DMA_IDX_REG)
+    }
+
+    pub fn clear_interrupt(&mut self) {
+        unsafe { ptr::write_volatile(DMA_CLEAR_INT_REG as *mut u16, 1 << self.index) }
+    }
+
+    /// Register a handler which is invoked from the DMA interrupt for this channel
+    pub fn register_handler(&self, handler: fn()) {
+        set_handler(self.index, Some(handler));
+    }
+
+    pub fn unregister_handler(&self) {
+        set_handler(self.index, None);
+    }
+}
+
+/// A pair of DMA channels sharing the request lines of one peripheral
+pub struct ChannelPair {
+    /// Channel serving the receive request (peripheral to memory)
+    pub rx: Channel,
+    /// Channel serving the transmit request (memory to peripheral)
+    pub tx: Channel,
+}
+
+impl ChannelPair {
+    /// Connect the request lines of a peripheral to this channel pair
+    pub fn set_trigger(&mut self, trigger: Trigger) {
+        let shift = if self.rx.index == 0 { 0 } else { 4 };
+
+        interrupt::free(|_| unsafe {
+            let mux = ptr::read_volatile(DMA_REQ_MUX_REG as *const u16);
+            let mux = (mux & !(0xF << shift)) | ((trigger as u16) << shift);
+            ptr::write_volatile(DMA_REQ_MUX_REG as *mut u16, mux);
+        });
+    }
+
+    pub fn stop(&mut self) {
+        self.rx.stop();
+        self.tx.stop();
+    }
+}
+
+/// The DMA controller, split into its two channel pairs
+pub struct Dma {
+    /// Channels 0 (rx) and 1 (tx)
+    pub ch01: ChannelPair,
+    /// Channels 2 (rx) and 3 (tx)
+    pub ch23: ChannelPair,
+}
+
+impl Dma {
+    /// Take the DMA controller and enable the DMA interrupt
+    ///
+    /// Returns `None` if the controller has already been taken.
+    pub fn take(nvic: &mut Nvic) -> Option<Self> {
+        let taken = interrupt::free(|cs| TAKEN.borrow(cs).replace(true));
+
+        if taken {
+            return None;
+        }
+
+        nvic.set_priority(Irq::Dma, 2);
+        nvic.enable_irq(Irq::Dma);
+
+        Some(Self {
+            ch01: ChannelPair {
+                rx: Channel { index: 0 },
+                tx: Channel { index: 1 },
+            },
+            ch23: ChannelPair {
+                rx: Channel { index: 2 },
+                tx: Channel { index: 3 },
+            },
+        })
+    }
+}
+
+pub(crate) fn set_handler(index: u8, handler: Option<fn()>) {
+    interrupt::free(|cs| DMA_HANDLERS.borrow(cs).borrow_mut()[index as usize] = handler);
+}
+
+/// DMA interrupt handler
+///
+/// # Safety
+///
+/// Must only be invoked by the NVIC.
+#[no_mangle]
+pub unsafe extern "C" fn DMA_Handler() {
+    let status = ptr::read_volatile(DMA_INT_STATUS_REG as *const u16);
+    let handlers = interrupt::free(|cs| *DMA_HANDLERS.borrow(cs).borrow());
+
+    // Called outside of the critical section, a handler may register or
+    // unregister handlers
+    for (index, handler) in handlers.iter().enumerate() {
+        if status & (1 << index) != 0 {
+            ptr::write_volatile(DMA_CLEAR_INT_REG as *mut u16, 1 << index);
+
+            if let Some(handler) = handler {
+                handler();
+            }
+        }
+    }
+}
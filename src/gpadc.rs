@@ -1,8 +1,58 @@
-use crate::pac::GPADC;
+use void::Void;
+
+use crate::{
+    crg_top::Clocks,
+    hal::adc::{Channel, OneShot},
+    otpc::{Otpc, SdkValue},
+    pac::GPADC,
+};
 
 pub mod config;
+pub mod continuous;
+pub mod conversion;
+pub mod dma;
+pub mod scan;
+
+pub use continuous::{RingSampling, Sampling};
+pub use conversion::Correction;
+pub use dma::{CircularTransfer, DmaTransfer};
+pub use scan::{Scan, ScanChannel};
+
+use config::{
+    AdcConfig, AdcInputExternal, AdcInputNegative, AdcInputPositive, AdcInputTemp, Averaging,
+    Chopper, Differential, InputMode, SampleTime,
+};
+use conversion::InputRange;
+
+/// Offset calibration rounds until the residual offset is small enough
+const CALIBRATION_ATTEMPTS: usize = 5;
+
+/// Largest residual offset of a successful calibration, in LSB of a 10 bit
+/// sample
+const MAX_RESIDUAL: u16 = 0x8;
+
+/// Mid-scale of a 10 bit sample, also the neutral offset adjustment
+const MID_SCALE: i32 = 0x200;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The residual offset after calibration is still too large
+    CalibrationFailed { residual: u16 },
+    /// Samples were dropped or overwritten before they were read
+    Overrun,
+}
 
-use config::{AdcConfig, Averaging, SampleTime};
+impl Correction {
+    /// Read the factory trims of an input mode from the OTP
+    fn from_otp(otpc: &Otpc, gain_error: SdkValue, offset: SdkValue) -> Self {
+        let value = |value| otpc.sdk_value(value).map_or(0, |value| value as u16 as i16);
+
+        Self {
+            gain_error: value(gain_error),
+            offset: value(offset),
+        }
+    }
+}
 
 /// Extension trait that constrains the `SYS_WDOG` peripheral
 pub trait GpAdcExt {
@@ -12,16 +62,82 @@ pub trait GpAdcExt {
 
 impl GpAdcExt for GPADC {
     fn constrain(self) -> GpAdc {
-        GpAdc { gpadc: self }
+        GpAdc {
+            gpadc: self,
+            hclk: 16_000_000,
+            pending: None,
+            single_ended: Correction::default(),
+            differential: Correction::default(),
+            temperature_25c: None,
+        }
     }
 }
 
 pub struct GpAdc {
     gpadc: GPADC,
+    /// CPU clock in Hz, used for busy waiting
+    hclk: u32,
+    /// Positive and negative input of the conversion started by
+    /// `OneShot::read`
+    pending: Option<(u8, Option<u8>)>,
+    single_ended: Correction,
+    differential: Correction,
+    /// Sample of the temperature sensor at 25 °C
+    temperature_25c: Option<u16>,
 }
 
 impl GpAdc {
-    pub fn calibrate_offset(&self, adc_trim_val: u16) {
+    /// Take the CPU clock from `clocks`, a 16 MHz clock is assumed otherwise
+    pub fn set_clocks(mut self, clocks: &Clocks) -> Self {
+        self.hclk = clocks.hclk().raw();
+        self
+    }
+
+    /// Set the gain and offset correction of samples in single ended and in
+    /// differential mode
+    pub fn set_correction(mut self, single_ended: Correction, differential: Correction) -> Self {
+        self.single_ended = single_ended;
+        self.differential = differential;
+        self
+    }
+
+    /// Set the corrected sample of the temperature sensor at 25 °C, used by
+    /// [`read_temperature`](Self::read_temperature)
+    pub fn set_temperature_calibration(mut self, sample: u16) -> Self {
+        self.temperature_25c = Some(sample);
+        self
+    }
+
+    /// Correct all samples with the factory trims stored in the OTP
+    ///
+    /// The OTPC must be enabled in read mode. Trims which are not programmed
+    /// are taken as zero. The temperature calibration point is taken over if
+    /// it is programmed.
+    pub fn load_trims(mut self, otpc: &Otpc) -> Self {
+        let single_ended = Correction::from_otp(
+            otpc,
+            SdkValue::AdcSingleEndedGainError,
+            SdkValue::AdcSingleEndedOffset,
+        );
+        let differential = Correction::from_otp(
+            otpc,
+            SdkValue::AdcDifferentialGainError,
+            SdkValue::AdcDifferentialOffset,
+        );
+
+        if let Some(sample) = otpc.sdk_value(SdkValue::AdcTemperature25C) {
+            self.temperature_25c = Some(sample as u16);
+        }
+
+        self.set_correction(single_ended, differential)
+    }
+
+    /// Calibrate the internal offset of the ADC
+    ///
+    /// Leaves the ADC initialized for the calibration, it has to be configured
+    /// for the measurements afterwards. Returns the residual offset in LSB of a
+    /// 10 bit sample.
+    pub fn calibrate_offset(&self, adc_trim_val: u16) -> Result<u16, Error> {
         // Configure for calibration
         self.init(
             AdcConfig::default()
@@ -31,59 +147,102 @@ impl GpAdc {
         );
         self.enable_20u_sink();
 
-        // Try once to calibrate correctly
+        let mut residual = u16::MAX;
 
-        self.gpadc
-            .gp_adc_offp_reg
-            .modify(|_, w| unsafe { w.gp_adc_offp().bits(0x200) });
-        self.gpadc
-            .gp_adc_offn_reg
-            .modify(|_, w| unsafe { w.gp_adc_offn().bits(0x200) });
-        self.gpadc
-            .gp_adc_ctrl_reg
-            .modify(|_, w| w.gp_adc_mute().set_bit().gp_adc_sign().clear_bit());
+        for _ in 0..CALIBRATION_ATTEMPTS {
+            self.set_offsets(MID_SCALE, MID_SCALE);
+            self.gpadc
+                .gp_adc_ctrl_reg
+                .modify(|_, w| w.gp_adc_mute().set_bit().gp_adc_sign().clear_bit());
 
-        self.start_conversion();
-        self.wait_for_conversion();
-        let adc_val = self.current_sample();
-        let adc_off_p = (adc_val >> 6) - 0x200;
+            let adc_off_p = self.sample_10bit() - MID_SCALE;
 
-        self.gpadc
-            .gp_adc_ctrl_reg
-            .modify(|_, w| w.gp_adc_sign().set_bit());
+            self.gpadc
+                .gp_adc_ctrl_reg
+                .modify(|_, w| w.gp_adc_sign().set_bit());
 
-        self.start_conversion();
-        self.wait_for_conversion();
-        let adc_val = self.current_sample();
-        let adc_off_n = (adc_val >> 6) - 0x200;
+            let adc_off_n = self.sample_10bit() - MID_SCALE;
 
-        self.gpadc
-            .gp_adc_offp_reg
-            .modify(|_, w| unsafe { w.gp_adc_offp().bits(0x200 - 2 * adc_off_p) });
+            self.set_offsets(MID_SCALE - 2 * adc_off_p, MID_SCALE - 2 * adc_off_n);
 
-        self.gpadc
-            .gp_adc_offn_reg
-            .modify(|_, w| unsafe { w.gp_adc_offn().bits(0x200 - 2 * adc_off_n) });
+            self.gpadc
+                .gp_adc_ctrl_reg
+                .modify(|_, w| w.gp_adc_sign().clear_bit());
+
+            // Verify the calibration result
+            residual = (self.sample_10bit() - MID_SCALE).unsigned_abs() as u16;
+
+            if residual < MAX_RESIDUAL {
+                break;
+            }
+        }
 
         self.gpadc
             .gp_adc_ctrl_reg
-            .modify(|_, w| w.gp_adc_sign().clear_bit());
+            .modify(|_, w| w.gp_adc_mute().clear_bit());
 
-        // Verify the calibration result
+        if residual < MAX_RESIDUAL {
+            Ok(residual)
+        } else {
+            Err(Error::CalibrationFailed { residual })
+        }
+    }
+
+    /// Measure the die temperature in hundredths of °C
+    ///
+    /// Converts the temperature sensor single ended with the chopper, 32 times
+    /// averaging and the longest sample time. Without a calibration point from
+    /// [`load_trims`](Self::load_trims) or
+    /// [`set_temperature_calibration`](Self::set_temperature_calibration) a
+    /// typical sensor is assumed, see [`conversion::centidegrees`].
+    ///
+    /// Reinitializes the ADC with the current trim value, it has to be
+    /// configured for other measurements afterwards.
+    pub fn read_temperature(&mut self) -> i32 {
+        let sample = self.measure(
+            AdcConfig::default()
+                .set_channel_pos(&AdcInputTemp)
+                .set_chopper_mode(Chopper::On)
+                .set_averaging(Averaging::SamplesX32)
+                .set_sample_time(SampleTime::Cycles15X8),
+        );
+
+        conversion::centidegrees(
+            sample,
+            self.temperature_25c.unwrap_or(conversion::TEMPERATURE_25C),
+        )
+    }
+
+    /// Reinitialize the ADC with `adc_config` and the current trim value, and
+    /// return the corrected sample of a single conversion
+    pub(crate) fn measure(&mut self, adc_config: AdcConfig) -> u16 {
+        let adc_trim_val = self.gpadc.gp_adc_trim_reg.read().bits();
+
+        self.pending = None;
+        self.init(adc_config.set_adc_trim_val(adc_trim_val));
 
         self.start_conversion();
         self.wait_for_conversion();
-        let adc_val = self.current_sample() >> 6;
 
-        let diff = if adc_val > 0x200 {
-            adc_val - 0x200
-        } else {
-            0x200 - adc_val
-        };
+        self.current_sample()
+    }
 
-        if diff >= 0x8 {
-            // Calibration failed
-        }
+    /// Convert and return the uncorrected sample reduced to 10 bits
+    fn sample_10bit(&self) -> i32 {
+        self.start_conversion();
+        self.wait_for_conversion();
+
+        (self.raw_sample() >> 6) as i32
+    }
+
+    /// Write the offset adjustments, limited to their 10 bit range
+    fn set_offsets(&self, offp: i32, offn: i32) {
+        self.gpadc
+            .gp_adc_offp_reg
+            .modify(|_, w| unsafe { w.gp_adc_offp().bits(offp.clamp(0, 0x3ff) as u16) });
+        self.gpadc
+            .gp_adc_offn_reg
+            .modify(|_, w| unsafe { w.gp_adc_offn().bits(offn.clamp(0, 0x3ff) as u16) });
     }
 
     pub fn init(&self, adc_config: AdcConfig) {
@@ -99,7 +258,16 @@ impl GpAdc {
         self.enable();
     }
 
+    /// # Panics
+    ///
+    /// Panics if the same input is selected on both sides in differential
+    /// mode.
     pub fn configure(&self, adc_config: AdcConfig) {
+        assert!(
+            adc_config.mode == InputMode::SingleEnded
+                || adc_config.channel_sel_pos != adc_config.channel_sel_neg
+        );
+
         self.gpadc.gp_adc_ctrl_reg.modify(|_, w| {
             w.gp_adc_se()
                 .bit(adc_config.mode.into())
@@ -119,12 +287,13 @@ impl GpAdc {
         });
 
         if adc_config.enable_die_temp {
-            // Guideline from the Analog IC Team: Wait for 25us to let the temperature
-            // sensor settle just after enabling it
-            // 25us*16MHz = 400 cylces
-            crate::cm::asm::delay(400);
+            self.settle_die_temp();
         }
 
+        self.gpadc
+            .gp_adc_ctrl3_reg
+            .modify(|_, w| unsafe { w.gp_adc_interval().bits(adc_config.interval) });
+
         self.gpadc.gp_adc_ctrl2_reg.modify(|_, w| unsafe {
             w.gp_adc_offs_sh_en()
                 .bit(adc_config.shifter.into())
@@ -137,6 +306,42 @@ impl GpAdc {
         });
     }
 
+    fn settle_die_temp(&self) {
+        // Guideline from the Analog IC Team: Wait for 25us to let the temperature
+        // sensor settle just after enabling it
+        crate::cm::asm::delay(25 * (self.hclk / 1_000_000));
+    }
+
+    /// Select a single ended input, or a differential pair if `negative` is
+    /// given, the temperature sensor is only powered while it is selected
+    ///
+    /// The chopper and the shifter are kept. The shifter moves both inputs of
+    /// a pair alike, so the difference is not affected.
+    fn select(&self, positive: u8, negative: Option<u8>) {
+        let die_temp = positive == AdcInputTemp::channel();
+        let settle = die_temp && !self.gpadc.gp_adc_ctrl_reg.read().die_temp_en().bit();
+
+        self.gpadc.gp_adc_sel_reg.modify(|_, w| unsafe {
+            w.gp_adc_sel_p().bits(positive);
+            match negative {
+                Some(negative) => w.gp_adc_sel_n().bits(negative),
+                None => w,
+            }
+        });
+        self.gpadc.gp_adc_ctrl_reg.modify(|_, w| {
+            w.gp_adc_se()
+                .bit(negative.is_none())
+                .gp_adc_cont()
+                .clear_bit()
+                .die_temp_en()
+                .bit(die_temp)
+        });
+
+        if settle {
+            self.settle_die_temp();
+        }
+    }
+
     /// Enable ADC peripheral
     pub fn enable(&self) {
         self.gpadc
@@ -214,40 +419,190 @@ impl GpAdc {
             .write(|w| unsafe { w.gp_adc_clr_int().bits(1) })
     }
 
-    /// Read current sample value from register
+    /// Check whether a conversion is running
+    pub fn is_converting(&self) -> bool {
+        self.gpadc
+            .gp_adc_ctrl_reg
+            .read()
+            .gp_adc_start()
+            .bit_is_set()
+    }
+
+    /// Read current sample value from register, corrected for the gain and
+    /// offset error of the configured input mode
     pub fn current_sample(&self) -> u16 {
-        self.gpadc.gp_adc_result_reg.read().gp_adc_val().bits()
+        self.correct_sample(self.raw_sample())
     }
 
-    pub fn correction_apply(&self, gain_error: i16, offset: i16, sample: u16) -> u16 {
-        let res = (u16::MAX as f32 * sample as f32) / (u16::MAX as f32 + gain_error as f32);
-        let res = res - (u16::MAX as f32 * offset as f32) / (u16::MAX as f32 + gain_error as f32);
+    /// Correct an uncorrected sample for the gain and offset error of the
+    /// configured input mode
+    pub fn correct_sample(&self, sample: u16) -> u16 {
+        correct(sample, self.correction())
+    }
 
-        // Boundary check for lower limit
-        if res > 2.0 * u16::MAX as f32 {
-            return 0;
+    /// Correction of the configured input mode
+    fn correction(&self) -> Correction {
+        match self.input_mode() {
+            InputMode::SingleEnded => self.single_ended,
+            InputMode::Differential => self.differential,
         }
+    }
 
-        // Boundary check for upper limit
-        if res > u16::MAX as f32 {
-            return u16::MAX;
-        }
+    /// Read the uncorrected sample value from register
+    pub fn raw_sample(&self) -> u16 {
+        self.gpadc.gp_adc_result_reg.read().gp_adc_val().bits()
+    }
 
-        res as u16
+    /// Correct a sample for a gain and offset error, see [`conversion`]
+    pub fn correction_apply(&self, gain_error: i16, offset: i16, sample: u16) -> u16 {
+        correct(sample, Correction { gain_error, offset })
     }
 
     pub fn has_shifter(&self) -> bool {
         self.gpadc.gp_adc_ctrl2_reg.read().gp_adc_offs_sh_en().bit()
     }
 
+    /// Input mode as currently configured
+    pub fn input_mode(&self) -> InputMode {
+        if self.gpadc.gp_adc_ctrl_reg.read().gp_adc_se().bit() {
+            InputMode::SingleEnded
+        } else {
+            InputMode::Differential
+        }
+    }
+
+    /// Convert a corrected sample to mV according to the current
+    /// configuration, see [`conversion`]
+    ///
+    /// The input range is 0.9 V times the attenuation, centered around zero in
+    /// differential mode. With the shifter the input range of 0.9 V starts at
+    /// the common mode voltage minus 450 mV.
+    pub fn convert_to_millivolts(&self, sample: u16) -> i32 {
+        conversion::millivolts(sample, self.input_range())
+    }
+
+    /// Convert a signed differential result to mV
+    pub fn convert_signed_to_millivolts(&self, value: i16) -> i32 {
+        self.convert_to_millivolts(unsigned(value))
+    }
+
+    /// Convert a corrected sample to V according to the current configuration
+    ///
+    /// The division pulls in `f32` soft-float code, [`convert_to_millivolts`]
+    /// does not.
+    ///
+    /// [`convert_to_millivolts`]: Self::convert_to_millivolts
     pub fn convert_to_voltage(&self, sample: u16) -> f32 {
-        // TODO: This need to be fixed
-        // 1bit = 1.526e-5 (1/0xffff)
-        // 0xffff => get_max_value() (calculated based on attenuation)
-        
-        // let attn = self.gpadc.gp_adc_ctrl2_reg.read().gp_adc_attn().bits() + 1;
-        let factor = 3.6 / 0xffff as f32;
+        self.convert_to_millivolts(sample) as f32 / 1000.0
+    }
+
+    /// Input range as currently configured
+    pub fn input_range(&self) -> InputRange {
+        let ctrl2 = self.gpadc.gp_adc_ctrl2_reg.read();
+        let shifter_low_mv = if ctrl2.gp_adc_offs_sh_en().bit() {
+            Some(800 + 50 * ctrl2.gp_adc_offs_sh_cm().bits() as i32)
+        } else {
+            None
+        };
+
+        InputRange::new(
+            self.input_mode(),
+            ctrl2.gp_adc_attn().bits() + 1,
+            shifter_low_mv,
+        )
+    }
+}
+
+/// Correct a sample with the implementation selected by the `float` feature
+///
+/// Without trims the sample is returned as it is, the `f32` arithmetic would
+/// lower some samples by one.
+fn correct(sample: u16, correction: Correction) -> u16 {
+    if correction == Correction::default() {
+        return sample;
+    }
+
+    #[cfg(feature = "float")]
+    return conversion::correct_f32(sample, correction);
+    #[cfg(not(feature = "float"))]
+    return conversion::correct(sample, correction);
+}
+
+/// ADC interrupt handler
+///
+/// # Safety
+///
+/// Must only be invoked by the NVIC.
+#[no_mangle]
+pub unsafe extern "C" fn ADC_Handler() {
+    continuous::on_interrupt();
+    scan::on_interrupt();
+}
+
+impl<PIN> OneShot<GPADC, u16, PIN> for GpAdc
+where
+    PIN: Channel<GPADC, ID = u8> + AdcInputPositive,
+{
+    type Error = Void;
+
+    /// Start a single ended conversion of `PIN` and return its result once it
+    /// has finished
+    ///
+    /// A finished conversion of another channel is discarded. The ADC has to
+    /// be initialized with [`GpAdc::init`] before.
+    fn read(&mut self, _pin: &mut PIN) -> nb::Result<u16, Self::Error> {
+        self.read_inputs(PIN::channel(), None)
+    }
+}
+
+impl<P, N> OneShot<GPADC, i16, Differential<P, N>> for GpAdc
+where
+    P: Channel<GPADC, ID = u8> + AdcInputExternal,
+    N: Channel<GPADC, ID = u8> + AdcInputNegative,
+{
+    type Error = Void;
+
+    /// Start a differential conversion of the pair and return its signed
+    /// result once it has finished
+    ///
+    /// A finished conversion of other inputs is discarded. The ADC has to be
+    /// initialized with [`GpAdc::init`] before.
+    fn read(&mut self, _pair: &mut Differential<P, N>) -> nb::Result<i16, Self::Error> {
+        self.read_inputs(P::channel(), Some(N::channel()))
+            .map(signed)
+    }
+}
+
+impl GpAdc {
+    fn read_inputs(&mut self, positive: u8, negative: Option<u8>) -> nb::Result<u16, Void> {
+        let inputs = (positive, negative);
+
+        match self.pending {
+            Some(_) if self.is_converting() => return Err(nb::Error::WouldBlock),
+            Some(pending) if pending == inputs => {
+                self.pending = None;
+                self.gpadc
+                    .gp_adc_clear_int_reg
+                    .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+
+                return Ok(self.current_sample());
+            }
+            _ => {}
+        }
+
+        self.select(positive, negative);
+        self.start_conversion();
+        self.pending = Some(inputs);
 
-        sample as f32 * factor
+        Err(nb::Error::WouldBlock)
     }
 }
+
+/// Differential sample as signed value, mid-scale is zero
+fn signed(sample: u16) -> i16 {
+    sample.wrapping_sub(0x8000) as i16
+}
+
+fn unsigned(value: i16) -> u16 {
+    (value as u16).wrapping_add(0x8000)
+}
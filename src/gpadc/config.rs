@@ -22,6 +22,10 @@ where
 {
 }
 
+/// One of the external inputs ADC0 to ADC3, the only ones which can be the
+/// positive side of a differential pair
+pub trait AdcInputExternal: AdcInputPositive {}
+
 macro_rules! adc_pin_entry {
     (PN: $pin:ty => $chan:expr) => {
         adc_pin_entry!(P: $pin => $chan);
@@ -29,6 +33,8 @@ macro_rules! adc_pin_entry {
         impl AdcInputNegative for $pin
         where
             Self: crate::hal::adc::Channel<crate::pac::GPADC, ID = u8> {}
+
+        impl AdcInputExternal for $pin {}
     };
     (P: $pin:ty => $chan:expr) => {
         impl crate::hal::adc::Channel<crate::pac::GPADC> for $pin {
@@ -63,6 +69,44 @@ adc_pins!(
     P: AdcInputVddd => 7
 );
 
+/// A positive and a negative input converted in differential mode
+///
+/// The pair owns both pins, so a pin cannot be selected for both sides. The
+/// result of a conversion is signed, zero when both inputs are at the same
+/// voltage.
+pub struct Differential<P, N> {
+    positive: P,
+    negative: N,
+}
+
+impl<P, N> Differential<P, N>
+where
+    P: Channel<GPADC, ID = u8> + AdcInputExternal,
+    N: Channel<GPADC, ID = u8> + AdcInputNegative,
+{
+    pub fn new(positive: P, negative: N) -> Self {
+        Self { positive, negative }
+    }
+
+    /// Release the pins
+    pub fn free(self) -> (P, N) {
+        (self.positive, self.negative)
+    }
+}
+
+impl<P, N> Channel<GPADC> for Differential<P, N>
+where
+    P: Channel<GPADC, ID = u8> + AdcInputExternal,
+    N: Channel<GPADC, ID = u8> + AdcInputNegative,
+{
+    /// Positive and negative input
+    type ID = (u8, u8);
+
+    fn channel() -> (u8, u8) {
+        (P::channel(), N::channel())
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum InputMode {
     Differential,
@@ -75,9 +119,9 @@ impl Default for InputMode {
     }
 }
 
-impl Into<bool> for InputMode {
-    fn into(self) -> bool {
-        match self {
+impl From<InputMode> for bool {
+    fn from(value: InputMode) -> Self {
+        match value {
             InputMode::Differential => false,
             InputMode::SingleEnded => true,
         }
@@ -96,9 +140,9 @@ impl Default for Chopper {
     }
 }
 
-impl Into<bool> for Chopper {
-    fn into(self) -> bool {
-        match self {
+impl From<Chopper> for bool {
+    fn from(value: Chopper) -> Self {
+        match value {
             Chopper::Off => false,
             Chopper::On => true,
         }
@@ -118,9 +162,9 @@ pub enum Continuous {
     Continuous,
 }
 
-impl Into<bool> for Continuous {
-    fn into(self) -> bool {
-        match self {
+impl From<Continuous> for bool {
+    fn from(value: Continuous) -> Self {
+        match value {
             Continuous::Single => false,
             Continuous::Continuous => true,
         }
@@ -205,16 +249,16 @@ impl Default for Shifter {
     }
 }
 
-impl Into<bool> for Shifter {
-    fn into(self) -> bool {
-        match self {
+impl From<Shifter> for bool {
+    fn from(value: Shifter) -> Self {
+        match value {
             Shifter::Off => false,
             Shifter::On => true,
         }
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 pub struct AdcConfig {
     pub(crate) mode: InputMode,
     pub(crate) chopper: Chopper,
@@ -224,36 +268,19 @@ pub struct AdcConfig {
     // pub(crate) mute: Mute,
     pub(crate) attenuation: Attenuation,
     pub(crate) continuous: Continuous,
+    pub(crate) interval: u8,
     pub(crate) channel_sel_pos: u8,
     pub(crate) channel_sel_neg: u8,
     pub(crate) enable_die_temp: bool, // pub(crate) vddd: u32
     pub(crate) adc_trim_val: u16,
 }
 
-impl Default for AdcConfig {
-    fn default() -> Self {
-        Self {
-            mode: Default::default(),
-            chopper: Default::default(),
-            sample_time: Default::default(),
-            averaging: Default::default(),
-            attenuation: Default::default(),
-            continuous: Default::default(),
-            shifter: Default::default(),
-            channel_sel_pos: 0,
-            channel_sel_neg: 0,
-            enable_die_temp: false,
-            adc_trim_val: 0,
-        }
-    }
-}
-
 impl AdcConfig {
     pub fn set_channel_pos<P: Channel<GPADC, ID = u8> + AdcInputPositive>(
         mut self,
         _pin: &P,
     ) -> Self {
-        let channel = P::channel() as u8;
+        let channel = P::channel();
         self.channel_sel_pos = channel;
 
         // Enable internal temp sensor (for channel 4)
@@ -265,10 +292,23 @@ impl AdcConfig {
     }
     pub fn set_channel_neg<N: Channel<GPADC, ID = u8> + AdcInputNegative>(
         mut self,
-        _pin: N,
+        _pin: &N,
     ) -> Self {
         self.mode = InputMode::Differential;
-        self.channel_sel_neg |= N::channel() as u8;
+        self.channel_sel_neg = N::channel();
+
+        self
+    }
+
+    /// Select both inputs of a differential pair
+    pub fn set_differential<P, N>(mut self, _pair: &Differential<P, N>) -> Self
+    where
+        P: Channel<GPADC, ID = u8> + AdcInputExternal,
+        N: Channel<GPADC, ID = u8> + AdcInputNegative,
+    {
+        self.mode = InputMode::Differential;
+        self.channel_sel_pos = P::channel();
+        self.channel_sel_neg = N::channel();
 
         self
     }
@@ -298,6 +338,13 @@ impl AdcConfig {
         self
     }
 
+    /// Wait `interval` times 1.024 ms between two conversions in continuous
+    /// mode
+    pub fn set_interval(mut self, interval: u8) -> Self {
+        self.interval = interval;
+        self
+    }
+
     pub fn set_adc_trim_val(mut self, val: u16) -> Self {
         self.adc_trim_val = val;
         self
@@ -0,0 +1,326 @@
+//! Continuous conversions collected by the ADC interrupt.
+//!
+//! In continuous mode the ADC converts the configured input again and again,
+//! with the interval set by [`AdcConfig::set_interval`]. `ADC_Handler` stores
+//! every corrected sample, either into a buffer until it is full
+//! ([`GpAdc::start_sampling`]) or into a ring buffer which is read while the
+//! ADC keeps converting ([`GpAdc::start_ring_sampling`]). Both stop after an
+//! optional number of samples.
+//!
+//! [`AdcConfig::set_interval`]: super::config::AdcConfig::set_interval
+
+use core::{cell::RefCell, ptr};
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    nvic::{Irq, Nvic},
+    pac::{gpadc::RegisterBlock, GPADC},
+};
+
+use super::{Correction, Error, GpAdc};
+
+struct State {
+    running: bool,
+    buffer: *mut u16,
+    len: usize,
+    /// Samples are kept until they are read instead of stopping once the
+    /// buffer is full
+    ring: bool,
+    /// Index of the next sample written
+    head: usize,
+    /// Index of the oldest sample in the ring buffer
+    tail: usize,
+    /// Number of samples in the buffer
+    count: usize,
+    /// Samples left until the conversions stop
+    remaining: Option<usize>,
+    overrun: bool,
+    correction: Correction,
+}
+
+// The buffer pointer is only dereferenced within a critical section while the
+// owning `Sampling` or `RingSampling` keeps the buffer alive.
+unsafe impl Send for State {}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            buffer: ptr::null_mut(),
+            len: 0,
+            ring: false,
+            head: 0,
+            tail: 0,
+            count: 0,
+            remaining: None,
+            overrun: false,
+            correction: Correction {
+                gain_error: 0,
+                offset: 0,
+            },
+        }
+    }
+
+    /// Store a sample, returns whether the conversions have to stop
+    fn push(&mut self, sample: u16) -> bool {
+        if self.count == self.len {
+            // The oldest sample has not been read yet
+            self.overrun = true;
+        } else {
+            unsafe { *self.buffer.add(self.head) = sample };
+            self.head = self.next(self.head);
+            self.count += 1;
+        }
+
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+
+        self.remaining == Some(0) || (!self.ring && self.count == self.len)
+    }
+
+    fn pop(&mut self) -> Option<u16> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let sample = unsafe { *self.buffer.add(self.tail) };
+        self.tail = self.next(self.tail);
+        self.count -= 1;
+
+        Some(sample)
+    }
+
+    /// Index following `index`, wrapped around at the end of the buffer
+    fn next(&self, index: usize) -> usize {
+        if index + 1 == self.len {
+            0
+        } else {
+            index + 1
+        }
+    }
+}
+
+fn regs() -> &'static RegisterBlock {
+    unsafe { &*GPADC::ptr() }
+}
+
+/// Stop converting once the running conversion has finished
+fn stop(regs: &RegisterBlock) {
+    regs.gp_adc_ctrl_reg
+        .modify(|_, w| w.gp_adc_cont().clear_bit().gp_adc_mint().clear_bit());
+}
+
+/// Samples of the configured input written into a buffer
+///
+/// The sampling owns the ADC and the buffer until it is stopped.
+pub struct Sampling {
+    adc: GpAdc,
+    buffer: &'static mut [u16],
+}
+
+impl Sampling {
+    /// Check whether the buffer is full or the number of samples is reached
+    pub fn is_done(&self) -> bool {
+        interrupt::free(|cs| !STATE.borrow(cs).borrow().running)
+    }
+
+    /// Number of samples written so far
+    pub fn len(&self) -> usize {
+        interrupt::free(|cs| STATE.borrow(cs).borrow().count)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Block until the sampling is done, see [`stop`](Self::stop)
+    pub fn wait(self) -> (GpAdc, &'static mut [u16], usize) {
+        while !self.is_done() {}
+
+        self.stop()
+    }
+
+    /// Stop converting and release the ADC
+    ///
+    /// Returns the buffer together with the number of samples written to it.
+    pub fn stop(self) -> (GpAdc, &'static mut [u16], usize) {
+        let len = finish(&self.adc);
+
+        (self.adc, self.buffer, len)
+    }
+}
+
+/// Samples of the configured input collected in a ring buffer
+///
+/// The sampling owns the ADC and the buffer until it is stopped.
+pub struct RingSampling {
+    adc: GpAdc,
+    buffer: &'static mut [u16],
+}
+
+impl RingSampling {
+    /// Take the oldest sample from the ring buffer
+    ///
+    /// Returns `nb::Error::WouldBlock` while the ring buffer is empty. Once
+    /// samples have been dropped because the ring buffer was full,
+    /// [`Error::Overrun`] is returned a single time before the next sample.
+    pub fn read(&mut self) -> nb::Result<u16, Error> {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            if state.overrun {
+                state.overrun = false;
+                return Err(nb::Error::Other(Error::Overrun));
+            }
+
+            state.pop().ok_or(nb::Error::WouldBlock)
+        })
+    }
+
+    /// Check whether the number of samples is reached
+    ///
+    /// Samples may still be left in the ring buffer.
+    pub fn is_done(&self) -> bool {
+        interrupt::free(|cs| !STATE.borrow(cs).borrow().running)
+    }
+
+    /// Stop converting and release the ADC and the buffer
+    pub fn stop(self) -> (GpAdc, &'static mut [u16]) {
+        finish(&self.adc);
+
+        (self.adc, self.buffer)
+    }
+}
+
+/// Stop the conversions and reset the state, returns the number of samples
+/// written
+fn finish(adc: &GpAdc) -> usize {
+    let regs = &adc.gpadc;
+
+    let count = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        let count = state.count;
+
+        stop(regs);
+        *state = State::new();
+
+        count
+    });
+
+    while adc.is_converting() {}
+    regs.gp_adc_clear_int_reg
+        .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+
+    count
+}
+
+impl GpAdc {
+    /// Convert the configured input continuously and write the samples into
+    /// `buffer` until it is full, or until `count` samples are written
+    ///
+    /// The ADC has to be initialized with [`GpAdc::init`] before.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer` is empty.
+    pub fn start_sampling(
+        self,
+        buffer: &'static mut [u16],
+        count: Option<usize>,
+        nvic: &mut Nvic,
+    ) -> Sampling {
+        self.start_continuous(buffer.as_mut_ptr(), buffer.len(), false, count, nvic);
+
+        Sampling { adc: self, buffer }
+    }
+
+    /// Convert the configured input continuously and collect the samples in
+    /// the ring buffer `buffer`, stop after `count` samples if given
+    ///
+    /// The ADC has to be initialized with [`GpAdc::init`] before.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer` is empty.
+    pub fn start_ring_sampling(
+        self,
+        buffer: &'static mut [u16],
+        count: Option<usize>,
+        nvic: &mut Nvic,
+    ) -> RingSampling {
+        self.start_continuous(buffer.as_mut_ptr(), buffer.len(), true, count, nvic);
+
+        RingSampling { adc: self, buffer }
+    }
+
+    fn start_continuous(
+        &self,
+        buffer: *mut u16,
+        len: usize,
+        ring: bool,
+        count: Option<usize>,
+        nvic: &mut Nvic,
+    ) {
+        assert!(len > 0);
+
+        let correction = self.correction();
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            *state = State {
+                running: count != Some(0),
+                buffer,
+                len,
+                ring,
+                remaining: count,
+                correction,
+                ..State::new()
+            };
+
+            if !state.running {
+                return;
+            }
+
+            self.gpadc
+                .gp_adc_clear_int_reg
+                .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+            self.gpadc
+                .gp_adc_ctrl_reg
+                .modify(|_, w| w.gp_adc_cont().set_bit().gp_adc_mint().set_bit());
+            self.start_conversion();
+        });
+
+        nvic.set_priority(Irq::Adc, 2);
+        nvic.enable_irq(Irq::Adc);
+    }
+}
+
+/// Store the finished conversion, called from `ADC_Handler`
+pub(super) fn on_interrupt() {
+    let regs = regs();
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !state.running {
+            return;
+        }
+
+        regs.gp_adc_clear_int_reg
+            .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+
+        let sample = super::correct(
+            regs.gp_adc_result_reg.read().gp_adc_val().bits(),
+            state.correction,
+        );
+
+        if state.push(sample) {
+            stop(regs);
+            state.running = false;
+        }
+    });
+}
@@ -0,0 +1,387 @@
+//! Gain and offset correction of samples and conversion to millivolts.
+//!
+//! The Cortex-M0+ has neither an FPU nor a hardware divider, so both are done
+//! with integer arithmetic by default:
+//!
+//! - [`correct`] returns the same sample as the `f32` implementation, bit for
+//!   bit. It rounds every intermediate result to the 24 bit mantissa of an
+//!   `f32` like the floating point unit, and checks the limits in the same
+//!   order. As the `f32` implementation, it returns zero for results above
+//!   twice the full scale, which only occur with extreme trims. Apart from
+//!   these the result is within 1 LSB of the exact corrected sample. Without
+//!   trims the sample is returned unchanged, where `f32` would lower some
+//!   samples by one.
+//! - [`millivolts`] rounds to the nearest mV, half a mV is rounded up. The
+//!   result is within 0.5 mV of the exact value.
+//! - [`centidegrees`] rounds to the nearest hundredth of a degree Celsius the
+//!   same way.
+//!
+//! The `float` feature switches the correction back to the `f32`
+//! implementation of `correct_f32`.
+
+use super::config::InputMode;
+
+/// Gain and offset error of the ADC, in LSB of a 16 bit sample
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Correction {
+    pub gain_error: i16,
+    pub offset: i16,
+}
+
+/// Input range of the ADC
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputRange {
+    /// From zero to the full scale in mV
+    SingleEnded { full_scale_mv: i32 },
+    /// From minus to plus the full scale in mV
+    Differential { full_scale_mv: i32 },
+    /// 900 mV starting at `low_mv`, with the input shifter
+    Shifted { low_mv: i32 },
+}
+
+impl InputRange {
+    /// Range of `mode`, `attenuation` is the factor from 1 to 4
+    pub fn new(mode: InputMode, attenuation: u8, shifter_low_mv: Option<i32>) -> Self {
+        let full_scale_mv = 900 * attenuation as i32;
+
+        match (mode, shifter_low_mv) {
+            (InputMode::Differential, _) => InputRange::Differential { full_scale_mv },
+            (InputMode::SingleEnded, Some(low_mv)) => InputRange::Shifted { low_mv },
+            (InputMode::SingleEnded, None) => InputRange::SingleEnded { full_scale_mv },
+        }
+    }
+}
+
+/// Positive number `m * 2^e` as an `f32` holds it, `m` has at most 24 bits
+#[derive(Clone, Copy)]
+struct Float {
+    m: u64,
+    e: i32,
+}
+
+impl Float {
+    /// Round `n * 2^e` to 24 bits, to nearest with ties to even
+    ///
+    /// `inexact` marks a non-zero remainder below the lowest bit of `n`, which
+    /// then has to have more than 24 bits.
+    fn round(n: u64, inexact: bool, e: i32) -> Self {
+        let mut shift = (64 - n.leading_zeros()).saturating_sub(24);
+        let mut m = n >> shift;
+
+        if shift > 0 {
+            let rest = n & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+
+            if rest > half || (rest == half && (inexact || m & 1 == 1)) {
+                m += 1;
+            }
+
+            if m == 1 << 24 {
+                m >>= 1;
+                shift += 1;
+            }
+        }
+
+        Self {
+            m,
+            e: e + shift as i32,
+        }
+    }
+
+    fn from_int(n: u64) -> Self {
+        Self::round(n, false, 0)
+    }
+
+    /// `self / den`, `self` has to be an integer
+    fn div(self, den: u64) -> Self {
+        let bits = |n: u64| 64 - n.leading_zeros() as i32;
+        let num = self.m << self.e;
+
+        // At least 26 bits of quotient, so the remainder only decides ties
+        let k = (26 + bits(den) - bits(num)).max(0);
+        let num = num << k;
+
+        Self::round(num / den, num % den != 0, -k)
+    }
+
+    /// `self - other`, or `self + other` if `add`, returns the sign and the
+    /// magnitude of the result
+    fn sub(self, other: Self, add: bool) -> (bool, Self) {
+        let e = self.e.min(other.e);
+        let a = (self.m << (self.e - e)) as i64;
+        let b = (other.m << (other.e - e)) as i64;
+        let d = if add { a + b } else { a - b };
+
+        (d < 0, Self::round(d.unsigned_abs(), false, e))
+    }
+
+    fn gt(self, n: u64) -> bool {
+        if self.e >= 0 {
+            self.m << self.e > n
+        } else {
+            self.m > n << -self.e
+        }
+    }
+
+    /// Integer part
+    fn trunc(self) -> u64 {
+        if self.e >= 0 {
+            self.m << self.e
+        } else {
+            self.m >> -self.e
+        }
+    }
+}
+
+/// Correct a sample for the gain and offset error
+///
+/// Computes `(sample - offset) * 0xffff / (0xffff + gain_error)` with the
+/// rounding of `f32` arithmetic and limits it to the range of a sample.
+/// Results above twice the full scale are taken as negative and return zero.
+/// Without trims the sample is returned unchanged.
+pub fn correct(sample: u16, correction: Correction) -> u16 {
+    let Correction { gain_error, offset } = correction;
+
+    if gain_error == 0 && offset == 0 {
+        return sample;
+    }
+    let full_scale = u16::MAX as u64;
+    let den = (u16::MAX as i32 + gain_error as i32) as u64;
+
+    let res = Float::from_int(full_scale * sample as u64).div(den);
+    let sub = Float::from_int(full_scale * offset.unsigned_abs() as u64).div(den);
+    let (negative, res) = res.sub(sub, offset < 0);
+
+    // Boundary check for lower limit
+    if negative || res.gt(2 * full_scale) {
+        return 0;
+    }
+
+    // Boundary check for upper limit
+    if res.gt(full_scale) {
+        return u16::MAX;
+    }
+
+    res.trunc() as u16
+}
+
+/// Correct a sample for the gain and offset error with `f32` arithmetic
+#[cfg(any(test, feature = "float"))]
+pub fn correct_f32(sample: u16, correction: Correction) -> u16 {
+    let Correction { gain_error, offset } = correction;
+
+    let res = (u16::MAX as f32 * sample as f32) / (u16::MAX as f32 + gain_error as f32);
+    let res = res - (u16::MAX as f32 * offset as f32) / (u16::MAX as f32 + gain_error as f32);
+
+    // Boundary check for lower limit
+    if res > 2.0 * u16::MAX as f32 {
+        return 0;
+    }
+
+    // Boundary check for upper limit
+    if res > u16::MAX as f32 {
+        return u16::MAX;
+    }
+
+    res as u16
+}
+
+/// Convert a corrected sample to mV
+pub fn millivolts(sample: u16, range: InputRange) -> i32 {
+    let sample = sample as i64;
+
+    // The arithmetic shift rounds towards negative infinity, adding half an LSB
+    // before rounds to the nearest mV
+    let mv = match range {
+        InputRange::SingleEnded { full_scale_mv } => (sample * full_scale_mv as i64 + 0x8000) >> 16,
+        InputRange::Differential { full_scale_mv } => {
+            ((sample - 0x8000) * 2 * full_scale_mv as i64 + 0x8000) >> 16
+        }
+        InputRange::Shifted { low_mv } => low_mv as i64 + ((sample * 900 + 0x8000) >> 16),
+    };
+
+    mv as i32
+}
+
+/// Slope of the temperature sensor in µV per °C
+const TEMPERATURE_SLOPE_UV: i64 = 1450;
+
+/// Typical single ended sample of the temperature sensor at 25 °C, 473 mV
+pub const TEMPERATURE_25C: u16 = ((473 * 0x10000 + 450) / 900) as u16;
+
+/// Convert a corrected single ended sample of the temperature sensor to
+/// hundredths of °C
+///
+/// `calibration` is the sample of the same sensor at 25 °C.
+pub fn centidegrees(sample: u16, calibration: u16) -> i32 {
+    let delta = sample as i64 - calibration as i64;
+
+    // One LSB is 900 mV / 2^16, the sensor rises by the slope per °C
+    let num = delta * 900_000 * 100;
+    let den = 0x10000 * TEMPERATURE_SLOPE_UV;
+
+    (2500 + (num + den / 2).div_euclid(den)) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERRORS: [i16; 15] = [
+        i16::MIN,
+        -32767,
+        -1000,
+        -37,
+        -2,
+        -1,
+        0,
+        1,
+        2,
+        255,
+        512,
+        1000,
+        16384,
+        32766,
+        i16::MAX,
+    ];
+
+    fn samples() -> impl Iterator<Item = u16> {
+        0..=u16::MAX
+    }
+
+    #[test]
+    fn correction_matches_float() {
+        for &gain_error in ERRORS.iter() {
+            for &offset in ERRORS.iter() {
+                let correction = Correction { gain_error, offset };
+
+                // Without trims the sample is kept, see `no_correction_is_identity`
+                if correction == Correction::default() {
+                    continue;
+                }
+
+                for sample in samples() {
+                    assert_eq!(
+                        correct(sample, correction),
+                        correct_f32(sample, correction),
+                        "{:?} {}",
+                        correction,
+                        sample
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn correction_is_within_one_lsb() {
+        for &gain_error in ERRORS.iter() {
+            for &offset in ERRORS.iter() {
+                let correction = Correction { gain_error, offset };
+
+                for sample in samples() {
+                    let exact = (sample as f64 - offset as f64) * u16::MAX as f64
+                        / (u16::MAX as f64 + gain_error as f64);
+
+                    // Taken as negative, as by the `f32` implementation
+                    if exact > 2.0 * u16::MAX as f64 {
+                        continue;
+                    }
+
+                    let exact = exact.clamp(0.0, u16::MAX as f64).trunc() as u16;
+                    let fixed = correct(sample, correction);
+
+                    assert!(
+                        fixed.abs_diff(exact) <= 1,
+                        "{:?} {}: {} != {}",
+                        correction,
+                        sample,
+                        fixed,
+                        exact
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_correction_is_identity() {
+        for sample in samples() {
+            assert_eq!(correct(sample, Correction::default()), sample);
+        }
+    }
+
+    #[test]
+    fn millivolts_match_float() {
+        let mut ranges = std::vec::Vec::new();
+
+        for attenuation in 1..=4 {
+            ranges.push(InputRange::new(InputMode::SingleEnded, attenuation, None));
+            ranges.push(InputRange::new(InputMode::Differential, attenuation, None));
+        }
+        for cm in 0..4 {
+            ranges.push(InputRange::new(
+                InputMode::SingleEnded,
+                1,
+                Some(800 + 50 * cm),
+            ));
+        }
+
+        for range in ranges {
+            for sample in samples() {
+                let x = sample as f64 / 65536.0;
+                let exact = match range {
+                    InputRange::SingleEnded { full_scale_mv } => x * full_scale_mv as f64,
+                    InputRange::Differential { full_scale_mv } => {
+                        (2.0 * x - 1.0) * full_scale_mv as f64
+                    }
+                    InputRange::Shifted { low_mv } => low_mv as f64 + x * 900.0,
+                };
+
+                let mv = millivolts(sample, range);
+
+                assert_eq!(mv, (exact + 0.5).floor() as i32, "{:?} {}", range, sample);
+                assert!((mv as f64 - exact).abs() <= 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn millivolts_cover_the_range() {
+        let range = InputRange::new(InputMode::Differential, 4, None);
+
+        assert_eq!(millivolts(0, range), -3600);
+        assert_eq!(millivolts(0x8000, range), 0);
+        assert_eq!(millivolts(u16::MAX, range), 3600);
+
+        let range = InputRange::new(InputMode::SingleEnded, 2, None);
+
+        assert_eq!(millivolts(0, range), 0);
+        assert_eq!(millivolts(u16::MAX, range), 1800);
+    }
+
+    #[test]
+    fn differential_ignores_the_shifter() {
+        assert_eq!(
+            InputRange::new(InputMode::Differential, 1, Some(850)),
+            InputRange::Differential { full_scale_mv: 900 }
+        );
+    }
+
+    #[test]
+    fn centidegrees_follow_the_slope() {
+        assert_eq!(centidegrees(TEMPERATURE_25C, TEMPERATURE_25C), 2500);
+
+        for sample in samples() {
+            let exact = 2500.0
+                + (sample as f64 - TEMPERATURE_25C as f64) * 900_000.0 * 100.0 / (65536.0 * 1450.0);
+
+            assert_eq!(
+                centidegrees(sample, TEMPERATURE_25C),
+                (exact + 0.5).floor() as i32,
+                "{}",
+                sample
+            );
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! DMA backed continuous conversions.
+//!
+//! In continuous mode with DMA enabled the ADC requests a transfer of
+//! `GP_ADC_RESULT_REG` after every conversion. The receive channel of a DMA
+//! channel pair moves the uncorrected samples into the buffer, without any
+//! work for the CPU. [`GpAdc::correct_sample`] corrects them afterwards.
+//!
+//! [`GpAdc::read_dma`] fills the buffer once. [`GpAdc::read_dma_circular`]
+//! keeps filling it, one half is processed while the DMA writes the other.
+
+use core::cell::RefCell;
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    dma::{BusWidth, Channel, ChannelConfig, ChannelPair, Trigger},
+    pac::{gpadc::RegisterBlock, GPADC},
+};
+
+use super::{Error, GpAdc};
+
+struct State {
+    running: bool,
+    circular: bool,
+    channel: u8,
+    /// Length of the buffer in samples
+    len: u16,
+    /// Number of halves filled by a circular transfer
+    filled: usize,
+    /// Number of halves processed
+    processed: usize,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            circular: false,
+            channel: 0,
+            len: 0,
+            filled: 0,
+            processed: 0,
+        }
+    }
+}
+
+fn regs() -> &'static RegisterBlock {
+    unsafe { &*GPADC::ptr() }
+}
+
+/// Stop converting and requesting transfers
+fn stop(regs: &RegisterBlock) {
+    regs.gp_adc_ctrl_reg
+        .modify(|_, w| w.gp_adc_cont().clear_bit().gp_adc_dma_en().clear_bit());
+}
+
+/// A single DMA transfer of samples into a buffer
+///
+/// The transfer owns the ADC, the DMA channels and the buffer until it has
+/// finished, they are handed back by [`DmaTransfer::wait`].
+pub struct DmaTransfer {
+    adc: GpAdc,
+    dma: ChannelPair,
+    buffer: &'static mut [u16],
+}
+
+impl DmaTransfer {
+    /// Check whether the buffer is full
+    pub fn is_done(&self) -> bool {
+        interrupt::free(|cs| !STATE.borrow(cs).borrow().running)
+    }
+
+    /// Number of samples transferred so far
+    pub fn transferred(&self) -> usize {
+        self.dma.rx.transferred() as usize
+    }
+
+    /// Block until the buffer is full
+    ///
+    /// Returns the ADC, the DMA channels and the buffer.
+    pub fn wait(self) -> (GpAdc, ChannelPair, &'static mut [u16]) {
+        while !self.is_done() {}
+
+        finish(&self.adc);
+
+        (self.adc, self.dma, self.buffer)
+    }
+}
+
+/// A circular DMA transfer which keeps filling the two halves of a buffer
+///
+/// The transfer owns the ADC, the DMA channels and the buffer until it is
+/// stopped.
+pub struct CircularTransfer {
+    adc: GpAdc,
+    dma: ChannelPair,
+    buffer: &'static mut [u16],
+}
+
+impl CircularTransfer {
+    /// Process the half of the buffer which has been filled next
+    ///
+    /// Returns `nb::Error::WouldBlock` until a half is filled.
+    /// [`Error::Overrun`] is returned if `f` was not called before the DMA
+    /// wrapped around into the half, or if the DMA has started to overwrite
+    /// the half while `f` was running. Processing continues with the most
+    /// recent half then.
+    pub fn read_half<R>(&mut self, f: impl FnOnce(&[u16]) -> R) -> nb::Result<R, Error> {
+        let half = interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            match state.filled - state.processed {
+                0 => Err(nb::Error::WouldBlock),
+                1 => Ok(state.processed % 2),
+                _ => {
+                    state.processed = state.filled - 1;
+                    Err(nb::Error::Other(Error::Overrun))
+                }
+            }
+        })?;
+
+        let half_len = self.buffer.len() / 2;
+        let result = f(&self.buffer[half * half_len..(half + 1) * half_len]);
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            // The DMA has filled the other half as well and wrapped around
+            if state.filled - state.processed > 1 {
+                state.processed = state.filled - 1;
+                return Err(nb::Error::Other(Error::Overrun));
+            }
+
+            state.processed += 1;
+            Ok(result)
+        })
+    }
+
+    /// Stop converting and release the ADC, the DMA channels and the buffer
+    pub fn stop(self) -> (GpAdc, ChannelPair, &'static mut [u16]) {
+        finish(&self.adc);
+
+        (self.adc, self.dma, self.buffer)
+    }
+}
+
+/// Stop the ADC and the receive channel and reset the state
+fn finish(adc: &GpAdc) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        stop(&adc.gpadc);
+
+        let mut channel = unsafe { Channel::steal(state.channel) };
+        channel.stop();
+        channel.unregister_handler();
+
+        *state = State::new();
+    });
+
+    while adc.is_converting() {}
+    adc.gpadc
+        .gp_adc_clear_int_reg
+        .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+}
+
+impl GpAdc {
+    /// Convert the configured input continuously until `buffer` is full,
+    /// using the receive channel of a DMA channel pair
+    ///
+    /// The ADC has to be initialized with [`GpAdc::init`] before. The
+    /// samples are not corrected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer` is empty or longer than 65535 samples.
+    pub fn read_dma(self, dma: ChannelPair, buffer: &'static mut [u16]) -> DmaTransfer {
+        let mut transfer = DmaTransfer {
+            adc: self,
+            dma,
+            buffer,
+        };
+
+        start(
+            &transfer.adc,
+            &mut transfer.dma,
+            transfer.buffer.as_mut_ptr(),
+            transfer.buffer.len(),
+            false,
+        );
+
+        transfer
+    }
+
+    /// Convert the configured input continuously into the two halves of
+    /// `buffer` in turn, using the receive channel of a DMA channel pair
+    ///
+    /// The ADC has to be initialized with [`GpAdc::init`] before. The
+    /// samples are not corrected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `buffer` is odd, zero or larger than 65534
+    /// samples.
+    pub fn read_dma_circular(
+        self,
+        dma: ChannelPair,
+        buffer: &'static mut [u16],
+    ) -> CircularTransfer {
+        assert!(buffer.len() % 2 == 0);
+
+        let mut transfer = CircularTransfer {
+            adc: self,
+            dma,
+            buffer,
+        };
+
+        start(
+            &transfer.adc,
+            &mut transfer.dma,
+            transfer.buffer.as_mut_ptr(),
+            transfer.buffer.len(),
+            true,
+        );
+
+        transfer
+    }
+}
+
+fn start(adc: &GpAdc, dma: &mut ChannelPair, buffer: *mut u16, len: usize, circular: bool) {
+    assert!(len > 0 && len <= u16::MAX as usize);
+
+    let len = len as u16;
+    let regs = &adc.gpadc;
+    let result = &regs.gp_adc_result_reg as *const _ as u32;
+
+    dma.set_trigger(Trigger::Adc);
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        *state = State {
+            running: true,
+            circular,
+            channel: dma.rx.index(),
+            len,
+            ..State::new()
+        };
+
+        let irq_at = if circular { len / 2 } else { len };
+
+        dma.rx.configure(
+            ChannelConfig::new(result, buffer as u32, len, BusWidth::HalfWord)
+                .set_src_inc(false)
+                .set_circular(circular)
+                .set_peripheral_request(true)
+                .set_irq_at(irq_at),
+        );
+        dma.rx.register_handler(on_rx);
+        dma.rx.start();
+
+        regs.gp_adc_clear_int_reg
+            .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+        regs.gp_adc_ctrl_reg.modify(|_, w| {
+            w.gp_adc_cont()
+                .set_bit()
+                .gp_adc_dma_en()
+                .set_bit()
+                .gp_adc_mint()
+                .clear_bit()
+        });
+        adc.start_conversion();
+    });
+}
+
+/// Account for a filled buffer or half, called from `DMA_Handler`
+fn on_rx() {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !state.running {
+            return;
+        }
+
+        if !state.circular {
+            stop(regs());
+            state.running = false;
+            return;
+        }
+
+        state.filled += 1;
+
+        // Raise the next interrupt at the end of the other half
+        let irq_at = if state.filled % 2 == 1 {
+            state.len
+        } else {
+            state.len / 2
+        };
+
+        unsafe { Channel::steal(state.channel) }.set_irq_at(irq_at);
+    });
+}
@@ -0,0 +1,311 @@
+//! Scans of several inputs stepped by the ADC interrupt.
+//!
+//! The ADC converts a single input at a time. A [`Scan`] converts a list of
+//! single ended inputs one after the other, each with its own attenuation,
+//! averaging and sample time. `ADC_Handler` stores the corrected sample and
+//! switches to the next input. Only the registers whose settings differ from
+//! the previous input are written.
+
+use core::cell::RefCell;
+
+use void::Void;
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    hal::adc::Channel,
+    nvic::{Irq, Nvic},
+    pac::{gpadc::RegisterBlock, GPADC},
+};
+
+use super::{
+    config::{AdcInputPositive, AdcInputTemp, Attenuation, Averaging, SampleTime},
+    Correction, GpAdc,
+};
+
+/// Largest number of inputs in a scan
+pub const MAX_CHANNELS: usize = 8;
+
+/// An input of a scan and its settings
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ScanChannel {
+    channel: u8,
+    attenuation: Attenuation,
+    averaging: Averaging,
+    sample_time: SampleTime,
+}
+
+impl ScanChannel {
+    pub fn new<P: Channel<GPADC, ID = u8> + AdcInputPositive>(_pin: &P) -> Self {
+        Self {
+            channel: P::channel(),
+            attenuation: Attenuation::default(),
+            averaging: Averaging::default(),
+            sample_time: SampleTime::default(),
+        }
+    }
+
+    pub fn set_attenuation(mut self, attenuation: Attenuation) -> Self {
+        self.attenuation = attenuation;
+        self
+    }
+
+    pub fn set_averaging(mut self, averaging: Averaging) -> Self {
+        self.averaging = averaging;
+        self
+    }
+
+    pub fn set_sample_time(mut self, sample_time: SampleTime) -> Self {
+        self.sample_time = sample_time;
+        self
+    }
+
+    /// Select the input and apply its settings, skipping the registers which
+    /// already hold them for the `previous` input
+    fn apply(&self, previous: Option<ScanChannel>, regs: &RegisterBlock) {
+        if previous.map_or(true, |previous| previous.channel != self.channel) {
+            regs.gp_adc_sel_reg
+                .modify(|_, w| unsafe { w.gp_adc_sel_p().bits(self.channel) });
+        }
+
+        let settings =
+            |channel: &ScanChannel| (channel.attenuation, channel.averaging, channel.sample_time);
+        if previous.map_or(false, |previous| settings(&previous) == settings(self)) {
+            return;
+        }
+
+        regs.gp_adc_ctrl2_reg.modify(|_, w| unsafe {
+            w.gp_adc_attn()
+                .bits(self.attenuation as u8)
+                .gp_adc_conv_nrs()
+                .bits(self.averaging as u8)
+                .gp_adc_smpl_time()
+                .bits(self.sample_time as u8)
+        });
+    }
+}
+
+struct State {
+    running: bool,
+    channels: [Option<ScanChannel>; MAX_CHANNELS],
+    results: [u16; MAX_CHANNELS],
+    /// Input which is being converted
+    index: usize,
+    /// A finished scan has not been read yet
+    done: bool,
+    /// Input whose settings are in the registers
+    applied: Option<ScanChannel>,
+    correction: Correction,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            channels: [None; MAX_CHANNELS],
+            results: [0; MAX_CHANNELS],
+            index: 0,
+            done: false,
+            applied: None,
+            correction: Correction {
+                gain_error: 0,
+                offset: 0,
+            },
+        }
+    }
+}
+
+fn regs() -> &'static RegisterBlock {
+    unsafe { &*GPADC::ptr() }
+}
+
+/// A scan of `N` single ended inputs
+///
+/// The scan owns the ADC until it is released by [`Scan::free`].
+pub struct Scan<const N: usize> {
+    adc: GpAdc,
+}
+
+impl GpAdc {
+    /// Scan `channels` in order, one after the other
+    ///
+    /// The ADC has to be initialized with [`GpAdc::init`] before, the
+    /// chopper and the shifter are kept for all inputs. The temperature
+    /// sensor is powered during the whole scan if it is one of the inputs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `N` is zero or larger than [`MAX_CHANNELS`].
+    pub fn into_scan<const N: usize>(
+        mut self,
+        channels: [ScanChannel; N],
+        nvic: &mut Nvic,
+    ) -> Scan<N> {
+        assert!(N > 0 && N <= MAX_CHANNELS);
+
+        let die_temp = channels
+            .iter()
+            .any(|channel| channel.channel == AdcInputTemp::channel());
+        let settle = die_temp && !self.gpadc.gp_adc_ctrl_reg.read().die_temp_en().bit();
+
+        self.pending = None;
+        self.gpadc.gp_adc_ctrl_reg.modify(|_, w| {
+            w.gp_adc_se()
+                .set_bit()
+                .gp_adc_cont()
+                .clear_bit()
+                .gp_adc_dma_en()
+                .clear_bit()
+                .die_temp_en()
+                .bit(die_temp)
+        });
+        // Left over from continuous conversions
+        self.gpadc
+            .gp_adc_ctrl3_reg
+            .modify(|_, w| unsafe { w.gp_adc_interval().bits(0) });
+
+        if settle {
+            self.settle_die_temp();
+        }
+
+        let correction = self.correction();
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            *state = State {
+                correction,
+                ..State::new()
+            };
+
+            for (slot, channel) in state.channels.iter_mut().zip(channels) {
+                *slot = Some(channel);
+            }
+        });
+
+        nvic.set_priority(Irq::Adc, 2);
+        nvic.enable_irq(Irq::Adc);
+
+        Scan { adc: self }
+    }
+}
+
+impl<const N: usize> Scan<N> {
+    /// Start a scan, unless one is running
+    ///
+    /// The results of a finished scan which has not been read are discarded.
+    pub fn start(&mut self) {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            if state.running {
+                return;
+            }
+
+            state.running = true;
+            state.done = false;
+            state.index = 0;
+
+            let regs = &self.adc.gpadc;
+
+            if let Some(channel) = state.channels[0] {
+                channel.apply(state.applied, regs);
+                state.applied = Some(channel);
+            }
+
+            regs.gp_adc_clear_int_reg
+                .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+            regs.gp_adc_ctrl_reg
+                .modify(|_, w| w.gp_adc_mint().set_bit());
+            self.adc.start_conversion();
+        });
+    }
+
+    /// Check whether a scan is running
+    pub fn is_running(&self) -> bool {
+        interrupt::free(|cs| STATE.borrow(cs).borrow().running)
+    }
+
+    /// Take the corrected samples of the finished scan, in the order of the
+    /// inputs
+    ///
+    /// Returns `nb::Error::WouldBlock` until a scan has finished.
+    pub fn read(&mut self) -> nb::Result<[u16; N], Void> {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            if !state.done {
+                return Err(nb::Error::WouldBlock);
+            }
+
+            state.done = false;
+
+            let mut results = [0; N];
+            results.copy_from_slice(&state.results[..N]);
+
+            Ok(results)
+        })
+    }
+
+    /// Stop scanning and release the ADC
+    ///
+    /// The ADC is left configured for the last input.
+    pub fn free(self) -> GpAdc {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            self.adc
+                .gpadc
+                .gp_adc_ctrl_reg
+                .modify(|_, w| w.gp_adc_mint().clear_bit());
+            *state = State::new();
+        });
+
+        while self.adc.is_converting() {}
+        self.adc
+            .gpadc
+            .gp_adc_clear_int_reg
+            .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+
+        self.adc
+    }
+}
+
+/// Store the sample and convert the next input, called from `ADC_Handler`
+pub(super) fn on_interrupt() {
+    let regs = regs();
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !state.running {
+            return;
+        }
+
+        regs.gp_adc_clear_int_reg
+            .write(|w| unsafe { w.gp_adc_clr_int().bits(1) });
+
+        let index = state.index;
+        state.results[index] = super::correct(
+            regs.gp_adc_result_reg.read().gp_adc_val().bits(),
+            state.correction,
+        );
+        state.index += 1;
+
+        match state.channels.get(state.index).copied().flatten() {
+            Some(channel) => {
+                channel.apply(state.applied, regs);
+                state.applied = Some(channel);
+                regs.gp_adc_ctrl_reg
+                    .modify(|_, w| w.gp_adc_start().set_bit());
+            }
+            None => {
+                regs.gp_adc_ctrl_reg
+                    .modify(|_, w| w.gp_adc_mint().clear_bit());
+                state.running = false;
+                state.done = true;
+            }
+        }
+    });
+}
@@ -246,6 +246,26 @@ impl StatefulOutputPin for Pin<Output> {
     }
 }
 
+/// Drive pin `pin` as GPIO output at `state`, or hand it back to the
+/// alternate function `pid` if `state` is `None`
+///
+/// Used by drivers which own a pin in alternate function mode and need a
+/// constant level the peripheral cannot generate.
+pub(crate) fn override_alternate(pin: u8, pid: u8, state: Option<PinState>) {
+    let block = unsafe { &*P0::ptr() };
+
+    match state {
+        Some(PinState::High) => block.p0_set_data_reg.write(|w| unsafe { w.bits(1 << pin) }),
+        Some(PinState::Low) => block
+            .p0_reset_data_reg
+            .write(|w| unsafe { w.bits(1 << pin) }),
+        None => {}
+    }
+
+    let pid = if state.is_some() { 0 } else { pid };
+    block.p0_mode_reg[pin as usize].modify(|_, w| unsafe { w.pid().bits(pid) });
+}
+
 macro_rules! gpio {
     (
         $PX:ident, $pxsvd:ident, $px:ident, [
@@ -1,14 +1,32 @@
 //! HAL interface to the I2C peripheral.
 
-use core::ops::Deref;
+use core::{cell::RefCell, ops::Deref};
 
 use crate::{
+    cm::interrupt::{self, Mutex},
     crg_top::CrgTop,
     gpio::{AfI2cScl, AfI2cSda, Pin},
     nvic::{Irq, Nvic},
-    pac::{i2c, I2C, NVIC},
+    pac::{i2c, I2C},
 };
 
+use embedded_hal_1::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+
+pub mod dma;
+pub mod eeprom;
+pub mod shared;
+pub mod transfer;
+
+pub use dma::DmaTransfer;
+pub use eeprom::{Eeprom, EepromConfig};
+pub use shared::{CriticalSectionBus, RefCellBus};
+pub use transfer::Transfer;
+
+/// Completion callback of non-blocking transfers
+type Handler = fn(Result<(), Error>);
+
+static I2C_HANDLER: Mutex<RefCell<Option<Handler>>> = Mutex::new(RefCell::new(None));
+
 /// Extension trait that constrains the `SYS_WDOG` peripheral
 pub trait I2cExt {
     /// Constrains the `SYS_WDOG` peripheral so it plays nicely with the other abstractions
@@ -22,6 +40,7 @@ impl I2cExt for I2C {
             pins: None,
             speed: Default::default(),
             addressing_mode: Default::default(),
+            target: None,
         }
     }
 }
@@ -41,6 +60,7 @@ impl Default for Speed {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddressingMode {
     Bits7,
     Bits10,
@@ -71,6 +91,8 @@ pub struct I2c {
     pins: Option<Pins>,
     speed: Speed,
     addressing_mode: AddressingMode,
+    /// Currently programmed slave address
+    target: Option<(u16, AddressingMode)>,
 }
 
 impl I2c {
@@ -96,12 +118,12 @@ impl I2c {
         assert!(self.pins.is_some());
 
         // Enable peripheral clock
-        CrgTop::enable_peripheral::<I2C>(&crg_top);
+        CrgTop::enable_peripheral::<I2C>(crg_top);
 
         // Disable the I2C Controller
         self.disable_controller();
 
-        // Enable all interrupts
+        // Mask all interrupts, non-blocking transfers unmask the ones they need
         self.i2c.i2c_intr_mask_reg.write(|w| unsafe { w.bits(0) });
 
         self.i2c
@@ -131,6 +153,9 @@ impl I2c {
             w.i2c_master_mode().set_bit();
             w.i2c_slave_disable().set_bit();
 
+            // Allow repeated START conditions
+            w.i2c_restart_en().set_bit();
+
             // Configure addressing mode
             match self.addressing_mode {
                 AddressingMode::Bits7 => {
@@ -155,113 +180,157 @@ impl I2c {
         // Enable the I2C Controller
         self.enable_controller();
 
+        self.target = None;
+
         nvic.set_priority(Irq::I2c, 2);
         nvic.enable_irq(Irq::I2c);
     }
 
-    fn send_byte(&self, byte: u8, stop: bool) -> Result<(), Error> {
-        self.wait_while_tx_fifo_full();
-
-        crate::cm::interrupt::free(|_| {
-            // Prepare to transmit the write command byte
-            self.i2c.i2c_data_cmd_reg.write(|w| {
-                w.i2c_cmd().clear_bit();
-
-                if stop {
-                    w.i2c_stop().set_bit();
-                }
+    /// Register a callback which is invoked from interrupt context once a
+    /// non-blocking transfer has finished
+    pub fn register_handler(&self, handler: fn(Result<(), Error>)) {
+        interrupt::free(|cs| *I2C_HANDLER.borrow(cs).borrow_mut() = Some(handler));
+    }
 
-                unsafe {
-                    w.dat().bits(byte);
-                }
+    /// Run a sequence of operations on the slave at `address` as a single
+    /// transaction
+    ///
+    /// The transaction starts with a START and ends with a STOP condition. A
+    /// repeated START is issued whenever the direction changes between two
+    /// operations, adjacent operations of the same kind are merged.
+    pub fn transaction(
+        &mut self,
+        address: u16,
+        addressing_mode: AddressingMode,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Error> {
+        self.set_target(address, addressing_mode);
 
-                w
-            });
+        let last = operations.iter().rposition(|op| match op {
+            Operation::Read(buffer) => !buffer.is_empty(),
+            Operation::Write(bytes) => !bytes.is_empty(),
         });
 
-        // Read the I2C_TX_ABRT_SOURCE_REG register
-        let abort_source = self.i2c.i2c_tx_abrt_source_reg.read().bits();
-        if abort_source != 0 {
-            self.i2c.i2c_clr_tx_abrt_reg.read().bits();
-            Err(Error::Transmit)
-        } else {
-            Ok(())
-        }
-    }
-
-    fn recv_byte(&self, stop: bool) -> Result<u8, Error> {
-        self.wait_while_tx_fifo_full();
+        let mut previous_read = None;
 
-        crate::cm::interrupt::free(|_| {
-            // Prepare to transmit the read command byte
-            self.i2c.i2c_data_cmd_reg.write(|w| {
-                if stop {
-                    w.i2c_stop().set_bit();
-                }
-                w.i2c_cmd().set_bit();
-                w
-            });
-        });
+        for (idx, operation) in operations.iter_mut().enumerate() {
+            let last_operation = Some(idx) == last;
 
-        // Wait for received data
-        while self.get_rx_fifo_bytes() == 0 {}
+            match operation {
+                Operation::Write(bytes) => {
+                    if bytes.is_empty() {
+                        continue;
+                    }
 
-        let out = self.i2c.i2c_data_cmd_reg.read().dat().bits();
+                    let restart = previous_read == Some(true);
+                    self.write_bytes(bytes, restart, last_operation)?;
+                    previous_read = Some(false);
+                }
+                Operation::Read(buffer) => {
+                    if buffer.is_empty() {
+                        continue;
+                    }
+
+                    let restart = previous_read == Some(false);
+                    self.read_bytes(buffer, restart, last_operation)?;
+                    previous_read = Some(true);
+                }
+            }
+        }
 
-        // Read the I2C_TX_ABRT_SOURCE_REG register
-        let abort_source = self.i2c.i2c_tx_abrt_source_reg.read().bits();
-        if abort_source != 0 {
-            self.i2c.i2c_clr_tx_abrt_reg.read().bits();
-            return Err(Error::Transmit);
+        if last.is_some() {
+            // Wait until the STOP condition has been sent
+            while self.i2c.i2c_status_reg.read().tfe().bit_is_clear()
+                || self.i2c.i2c_status_reg.read().mst_activity().bit_is_set()
+            {
+                self.check_abort()?;
+            }
         }
 
-        Ok(out)
+        self.check_abort()
     }
 
-    /// Write to an I2C slave.
-    fn write(&mut self, buffer: &[u8]) -> Result<(), Error> {
-        let buffer_length = buffer.len();
+    fn write_bytes(&mut self, bytes: &[u8], restart: bool, stop: bool) -> Result<(), Error> {
+        let length = bytes.len();
 
-        // Send out all bytes.
-        for (idx, byte) in buffer.iter().enumerate() {
-            self.send_byte(*byte, (idx + 1) == buffer_length)?;
-        }
+        for (idx, byte) in bytes.iter().enumerate() {
+            while self.i2c.i2c_status_reg.read().tfnf().bit_is_clear() {
+                self.check_abort()?;
+            }
 
-        self.wait_while_tx_fifo_not_completely_empty();
-        self.wait_while_master_busy();
+            self.i2c.i2c_data_cmd_reg.write(|w| {
+                w.i2c_cmd().clear_bit();
+                w.i2c_restart().bit(restart && idx == 0);
+                w.i2c_stop().bit(stop && idx + 1 == length);
+                unsafe { w.dat().bits(*byte) }
+            });
+        }
 
         Ok(())
     }
 
-    /// Read from an I2C slave.
-    fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
-        let buffer_length = buffer.len();
+    fn read_bytes(&mut self, buffer: &mut [u8], restart: bool, stop: bool) -> Result<(), Error> {
+        let length = buffer.len();
+        let mut issued = 0;
+        let mut received = 0;
+
+        while received < length {
+            // Never request more bytes than the RX FIFO is able to hold
+            while issued < length
+                && issued - received < transfer::FIFO_DEPTH
+                && self.i2c.i2c_status_reg.read().tfnf().bit_is_set()
+            {
+                self.i2c.i2c_data_cmd_reg.write(|w| {
+                    w.i2c_cmd().set_bit();
+                    w.i2c_restart().bit(restart && issued == 0);
+                    w.i2c_stop().bit(stop && issued + 1 == length)
+                });
+                issued += 1;
+            }
+
+            while received < issued && self.i2c.i2c_status_reg.read().rfne().bit_is_set() {
+                buffer[received] = self.i2c.i2c_data_cmd_reg.read().dat().bits();
+                received += 1;
+            }
 
-        // Read into buffer.
-        for (idx, byte) in buffer.iter_mut().enumerate() {
-            *byte = self.recv_byte((idx + 1) == buffer_length)?;
+            self.check_abort()?;
         }
 
         Ok(())
     }
 
-    /// Write data to an I2C slave, then read data from the slave without
-    /// triggering a stop condition between the two.
-    fn write_then_read(&mut self, wr_buffer: &[u8], rd_buffer: &mut [u8]) -> Result<(), Error> {
-        self.write(wr_buffer)?;
-        self.read(rd_buffer)?;
-        Ok(())
+    /// Check whether the current transfer has been aborted
+    fn check_abort(&self) -> Result<(), Error> {
+        match abort_error(&self.i2c) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
     }
 
-    fn set_slave_address(&mut self, address: u16) {
+    /// Select the slave for the next transfers
+    ///
+    /// The controller has to be disabled to change the target, this is only
+    /// done if the address or the addressing mode actually changes.
+    fn set_target(&mut self, address: u16, addressing_mode: AddressingMode) {
+        if self.target == Some((address, addressing_mode)) {
+            return;
+        }
+
         self.disable_controller();
 
+        self.i2c.i2c_con_reg.modify(|_, w| {
+            w.i2c_10bitaddr_master()
+                .bit(addressing_mode == AddressingMode::Bits10)
+        });
+
         // Set Slave I2C address.
         self.i2c
             .i2c_tar_reg
             .modify(|_, w| unsafe { w.ic_tar().bits(address) });
 
         self.enable_controller();
+
+        self.target = Some((address, addressing_mode));
     }
 
     fn enable_controller(&mut self) {
@@ -277,60 +346,113 @@ impl I2c {
             .modify(|_, w| w.ctrl_enable().clear_bit());
         while self.i2c.i2c_enable_reg.read().ctrl_enable().bit() {}
     }
+}
 
-    fn wait_while_tx_fifo_full(&self) {
-        while self.i2c.i2c_status_reg.read().tfnf().bit_is_clear() {}
+/// Read and clear the source of a pending transmit abort
+pub(crate) fn abort_error(i2c: &i2c::RegisterBlock) -> Option<Error> {
+    if i2c.i2c_raw_intr_stat_reg.read().tx_abrt().bit_is_clear() {
+        return None;
     }
 
-    fn wait_while_tx_fifo_not_completely_empty(&self) {
-        while self.i2c.i2c_status_reg.read().tfe().bit_is_clear() {}
-    }
+    let source = i2c.i2c_tx_abrt_source_reg.read();
+    i2c.i2c_clr_tx_abrt_reg.read();
+
+    let error = if source.abrt_7b_addr_noack().bit_is_set()
+        || source.abrt_10addr1_noack().bit_is_set()
+        || source.abrt_10addr2_noack().bit_is_set()
+    {
+        Error::AddressNack
+    } else if source.abrt_txdata_noack().bit_is_set() {
+        Error::DataNack
+    } else if source.arb_lost().bit_is_set() {
+        Error::ArbitrationLoss
+    } else {
+        Error::Transmit
+    };
+
+    Some(error)
+}
 
-    fn wait_while_master_busy(&self) {
-        while self.i2c.i2c_status_reg.read().mst_activity().bit_is_set() {}
-    }
+macro_rules! blocking_i2c {
+    ($($addr:ty => $mode:expr,)+) => {
+        $(
+            impl embedded_hal::blocking::i2c::Write<$addr> for I2c {
+                type Error = Error;
 
-    fn get_rx_fifo_bytes(&self) -> usize {
-        self.i2c.i2c_rxflr_reg.read().rxflr().bits().into()
-    }
-}
+                fn write(&mut self, addr: $addr, bytes: &[u8]) -> Result<(), Error> {
+                    self.transaction(addr as u16, $mode, &mut [Operation::Write(bytes)])
+                }
+            }
 
-impl embedded_hal::blocking::i2c::Write for I2c {
-    type Error = Error;
+            impl embedded_hal::blocking::i2c::Read<$addr> for I2c {
+                type Error = Error;
 
-    fn write<'w>(&mut self, addr: u8, bytes: &'w [u8]) -> Result<(), Error> {
-        self.set_slave_address(addr as u16);
-        self.write(bytes)
-    }
-}
+                fn read(&mut self, addr: $addr, buffer: &mut [u8]) -> Result<(), Error> {
+                    self.transaction(addr as u16, $mode, &mut [Operation::Read(buffer)])
+                }
+            }
 
-impl embedded_hal::blocking::i2c::Read for I2c {
-    type Error = Error;
+            impl embedded_hal::blocking::i2c::WriteRead<$addr> for I2c {
+                type Error = Error;
+
+                fn write_read(
+                    &mut self,
+                    addr: $addr,
+                    bytes: &[u8],
+                    buffer: &mut [u8],
+                ) -> Result<(), Error> {
+                    self.transaction(
+                        addr as u16,
+                        $mode,
+                        &mut [Operation::Write(bytes), Operation::Read(buffer)],
+                    )
+                }
+            }
 
-    fn read<'w>(&mut self, addr: u8, bytes: &'w mut [u8]) -> Result<(), Error> {
-        self.set_slave_address(addr as u16);
-        self.read(bytes)
-    }
+            impl embedded_hal_1::i2c::I2c<$addr> for I2c {
+                fn transaction(
+                    &mut self,
+                    address: $addr,
+                    operations: &mut [Operation<'_>],
+                ) -> Result<(), Error> {
+                    self.transaction(address as u16, $mode, operations)
+                }
+            }
+        )+
+    };
 }
 
-impl embedded_hal::blocking::i2c::WriteRead for I2c {
-    type Error = Error;
+blocking_i2c!(
+    u8 => AddressingMode::Bits7,
+    u16 => AddressingMode::Bits10,
+);
 
-    fn write_read<'w>(
-        &mut self,
-        addr: u8,
-        bytes: &'w [u8],
-        buffer: &'w mut [u8],
-    ) -> Result<(), Error> {
-        self.set_slave_address(addr as u16);
-        self.write_then_read(bytes, buffer)
-    }
+impl embedded_hal_1::i2c::ErrorType for I2c {
+    type Error = Error;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     Transmit,
     Receive,
+    /// The slave did not acknowledge its address
+    AddressNack,
+    /// The slave did not acknowledge a data byte
+    DataNack,
+    /// Another master won the arbitration
+    ArbitrationLoss,
+}
+
+impl embedded_hal_1::i2c::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::AddressNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address),
+            Error::DataNack => ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+            Error::ArbitrationLoss => ErrorKind::ArbitrationLoss,
+            Error::Receive => ErrorKind::Overrun,
+            Error::Transmit => ErrorKind::Other,
+        }
+    }
 }
 
 pub trait Instance: Deref<Target = i2c::RegisterBlock> + sealed::Sealed {}
@@ -342,12 +464,25 @@ mod sealed {
 impl sealed::Sealed for I2C {}
 impl Instance for I2C {}
 
+/// I2C interrupt handler
+///
+/// # Safety
+///
+/// Must only be invoked by the NVIC.
 #[no_mangle]
 pub unsafe extern "C" fn I2C_Handler() {
-    // if let Some(handler) = TIMER0_HANDLER {
-    //     handler();Nvic::
-    // }
-    NVIC::unpend(Irq::I2c);
+    transfer::on_interrupt();
+    dma::on_interrupt();
 }
 
-// static mut TIMER0_HANDLER: Option<fn()> = None;
+/// Invoke the registered completion callback
+fn notify(result: Result<(), Error>) {
+    // Called outside of the critical section. The transfer owns the
+    // peripheral until `wait` returns, so the handler cannot start the next
+    // transfer, it can only signal the code which waits.
+    let handler = interrupt::free(|cs| *I2C_HANDLER.borrow(cs).borrow());
+
+    if let Some(handler) = handler {
+        handler(result);
+    }
+}
@@ -0,0 +1,360 @@
+//! DMA backed I2C transfers.
+//!
+//! The transmit channel of a DMA channel pair feeds the TX FIFO with data bytes
+//! and read commands while the receive channel drains the RX FIFO into the
+//! buffer. The last command of a transfer carries the STOP bit, it is written by
+//! `I2C_Handler` once the transmit channel has finished.
+
+use core::cell::RefCell;
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    dma::{BusWidth, Channel, ChannelConfig, ChannelPair, Trigger},
+    pac::{i2c::RegisterBlock, I2C},
+};
+
+use super::{transfer::Status, AddressingMode, Error, I2c};
+
+/// Data/command word which requests one byte from the slave
+static READ_CMD: u16 = 1 << 8;
+
+/// Data/command bit which issues a STOP after the byte
+const STOP: u16 = 1 << 9;
+
+/// Data/command bit which issues a repeated START before the byte
+const RESTART: u16 = 1 << 10;
+
+/// Read command which turns the bus around after the written bytes
+static RESTART_READ_CMD: u16 = READ_CMD | RESTART;
+
+/// TX FIFO level at (or below) which the transmit DMA request is raised
+const TX_DMA_LEVEL: u8 = 2;
+
+struct State {
+    rx: u8,
+    tx: u8,
+    /// Read commands still to be pushed by the transmit channel
+    read_cmds: u16,
+    /// The first of them has to carry the RESTART bit
+    restart: bool,
+    /// Last command of the transfer, carries the STOP bit
+    final_cmd: Option<u16>,
+    rx_done: bool,
+    stopped: bool,
+    status: Status,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+fn data_cmd_addr(i2c: &RegisterBlock) -> u32 {
+    &i2c.i2c_data_cmd_reg as *const _ as u32
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            rx: 0,
+            tx: 0,
+            read_cmds: 0,
+            restart: false,
+            final_cmd: None,
+            rx_done: false,
+            stopped: false,
+            status: Status::Idle,
+        }
+    }
+
+    /// Start the next stage of the transmit channel
+    fn advance_tx(&mut self, i2c: &RegisterBlock) {
+        let mut tx = unsafe { Channel::steal(self.tx) };
+
+        if self.read_cmds > 0 {
+            // The restarting read command is pushed on its own, the channel
+            // finishes and comes back here for the remaining ones
+            let (cmd, count) = if self.restart {
+                (&RESTART_READ_CMD, 1)
+            } else {
+                (&READ_CMD, self.read_cmds)
+            };
+
+            tx.configure(
+                ChannelConfig::new(
+                    cmd as *const u16 as u32,
+                    data_cmd_addr(i2c),
+                    count,
+                    BusWidth::HalfWord,
+                )
+                .set_src_inc(false)
+                .set_dst_inc(false)
+                .set_peripheral_request(true),
+            );
+            self.restart = false;
+            self.read_cmds -= count;
+            tx.start();
+        } else {
+            // The TX_EMPTY interrupt pushes the final command
+            i2c.i2c_intr_mask_reg
+                .modify(|_, w| w.m_tx_empty().set_bit());
+        }
+    }
+
+    fn service(&mut self, i2c: &RegisterBlock) -> Option<Result<(), Error>> {
+        if !matches!(self.status, Status::Busy) {
+            return None;
+        }
+
+        let stat = i2c.i2c_intr_stat_reg.read();
+
+        if let Some(error) = super::abort_error(i2c) {
+            return Some(self.finish(i2c, Err(error)));
+        }
+
+        if stat.r_tx_empty().bit_is_set() {
+            if let Some(cmd) = self.final_cmd.take() {
+                i2c.i2c_data_cmd_reg.write(|w| unsafe { w.bits(cmd) });
+            }
+
+            i2c.i2c_intr_mask_reg
+                .modify(|_, w| w.m_tx_empty().clear_bit());
+        }
+
+        if stat.r_stop_det().bit_is_set() {
+            i2c.i2c_clr_stop_det_reg.read();
+            self.stopped = self.final_cmd.is_none();
+        }
+
+        self.try_finish(i2c)
+    }
+
+    fn try_finish(&mut self, i2c: &RegisterBlock) -> Option<Result<(), Error>> {
+        if self.stopped && self.rx_done {
+            Some(self.finish(i2c, Ok(())))
+        } else {
+            None
+        }
+    }
+
+    fn finish(&mut self, i2c: &RegisterBlock, result: Result<(), Error>) -> Result<(), Error> {
+        i2c.i2c_intr_mask_reg.write(|w| unsafe { w.bits(0) });
+        i2c.i2c_dma_cr_reg
+            .write(|w| w.tdmae().clear_bit().rdmae().clear_bit());
+
+        for index in [self.rx, self.tx] {
+            let mut channel = unsafe { Channel::steal(index) };
+            channel.stop();
+            channel.unregister_handler();
+        }
+
+        // Discard whatever is left over from an aborted transfer
+        while i2c.i2c_status_reg.read().rfne().bit_is_set() {
+            i2c.i2c_data_cmd_reg.read();
+        }
+
+        // Clears all individual interrupts and the abort source
+        i2c.i2c_clr_intr_reg.read();
+
+        self.status = Status::Done(result);
+        result
+    }
+}
+
+/// An I2C transfer performed by a DMA channel pair
+///
+/// The transfer owns the I2C peripheral, the DMA channels and the buffers until
+/// it has finished, they are handed back by [`DmaTransfer::wait`].
+pub struct DmaTransfer<B> {
+    i2c: I2c,
+    dma: ChannelPair,
+    buffer: B,
+}
+
+impl<B> DmaTransfer<B> {
+    fn start(
+        mut i2c: I2c,
+        mut dma: ChannelPair,
+        buffer: B,
+        address: u8,
+        (tx, tx_len): (*const u8, usize),
+        (rx, rx_len): (*mut u8, usize),
+    ) -> Self {
+        assert!(tx_len + rx_len > 0);
+        assert!(tx_len <= u16::MAX as usize && rx_len <= u16::MAX as usize);
+
+        i2c.set_target(address as u16, AddressingMode::Bits7);
+        dma.set_trigger(Trigger::I2c);
+
+        let regs = &i2c.i2c;
+        let data_cmd = data_cmd_addr(regs);
+
+        // Split the commands into the ones pushed by the transmit channel and
+        // the final one carrying the STOP bit
+        let (tx_stage, read_cmds, mut final_cmd) = if rx_len == 0 {
+            let last = unsafe { *tx.add(tx_len - 1) };
+            (tx_len - 1, 0, last as u16 | STOP)
+        } else {
+            (tx_len, rx_len - 1, READ_CMD | STOP)
+        };
+
+        // Turn the bus around with a repeated START before the first read,
+        // like the interrupt driven transfers do
+        let restart = tx_len > 0 && rx_len > 0;
+        if restart && read_cmds == 0 {
+            final_cmd |= RESTART;
+        }
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            *state = State {
+                rx: dma.rx.index(),
+                tx: dma.tx.index(),
+                read_cmds: read_cmds as u16,
+                restart: restart && read_cmds > 0,
+                final_cmd: Some(final_cmd),
+                rx_done: rx_len == 0,
+                stopped: false,
+                status: Status::Busy,
+            };
+
+            regs.i2c_clr_intr_reg.read();
+            regs.i2c_dma_tdlr_reg
+                .write(|w| unsafe { w.dmatdl().bits(TX_DMA_LEVEL) });
+            regs.i2c_dma_rdlr_reg
+                .write(|w| unsafe { w.dmardl().bits(0) });
+            regs.i2c_intr_mask_reg
+                .write(|w| w.m_tx_abrt().set_bit().m_stop_det().set_bit());
+
+            if rx_len > 0 {
+                dma.rx.configure(
+                    ChannelConfig::new(data_cmd, rx as u32, rx_len as u16, BusWidth::Byte)
+                        .set_src_inc(false)
+                        .set_peripheral_request(true),
+                );
+                dma.rx.register_handler(on_rx_done);
+                dma.rx.start();
+            }
+
+            dma.tx.register_handler(on_tx_done);
+
+            if tx_stage > 0 {
+                dma.tx.configure(
+                    ChannelConfig::new(tx as u32, data_cmd, tx_stage as u16, BusWidth::Byte)
+                        .set_dst_inc(false)
+                        .set_peripheral_request(true),
+                );
+                dma.tx.start();
+            } else {
+                state.advance_tx(regs);
+            }
+
+            regs.i2c_dma_cr_reg
+                .write(|w| w.tdmae().set_bit().rdmae().bit(rx_len > 0));
+        });
+
+        Self { i2c, dma, buffer }
+    }
+
+    /// Check whether the transfer has finished, either successfully or with an error
+    pub fn is_done(&self) -> bool {
+        !matches!(self.poll(), Err(nb::Error::WouldBlock))
+    }
+
+    /// Poll the result of the transfer
+    ///
+    /// Returns `nb::Error::WouldBlock` while the transfer is still in progress.
+    pub fn poll(&self) -> nb::Result<(), Error> {
+        interrupt::free(|cs| STATE.borrow(cs).borrow().status.poll())
+    }
+
+    /// Block until the transfer has finished
+    ///
+    /// Returns the result of the transfer together with the I2C peripheral, the
+    /// DMA channels and the buffers.
+    pub fn wait(self) -> (Result<(), Error>, I2c, ChannelPair, B) {
+        let result = nb::block!(self.poll());
+
+        interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = State::new());
+
+        (result, self.i2c, self.dma, self.buffer)
+    }
+}
+
+impl I2c {
+    /// Write `buffer` to the slave at `address` using a DMA channel pair
+    pub fn write_dma(
+        self,
+        dma: ChannelPair,
+        address: u8,
+        buffer: &'static [u8],
+    ) -> DmaTransfer<&'static [u8]> {
+        let tx = (buffer.as_ptr(), buffer.len());
+        DmaTransfer::start(self, dma, buffer, address, tx, (core::ptr::null_mut(), 0))
+    }
+
+    /// Read from the slave at `address` into `buffer` using a DMA channel pair
+    pub fn read_dma(
+        self,
+        dma: ChannelPair,
+        address: u8,
+        buffer: &'static mut [u8],
+    ) -> DmaTransfer<&'static mut [u8]> {
+        let rx = (buffer.as_mut_ptr(), buffer.len());
+        DmaTransfer::start(self, dma, buffer, address, (core::ptr::null(), 0), rx)
+    }
+
+    /// Write `wr_buffer` to the slave at `address`, then read into `rd_buffer`
+    /// using a DMA channel pair
+    pub fn write_read_dma(
+        self,
+        dma: ChannelPair,
+        address: u8,
+        wr_buffer: &'static [u8],
+        rd_buffer: &'static mut [u8],
+    ) -> DmaTransfer<(&'static [u8], &'static mut [u8])> {
+        let tx = (wr_buffer.as_ptr(), wr_buffer.len());
+        let rx = (rd_buffer.as_mut_ptr(), rd_buffer.len());
+        DmaTransfer::start(self, dma, (wr_buffer, rd_buffer), address, tx, rx)
+    }
+}
+
+fn on_tx_done() {
+    let i2c = unsafe { &*I2C::ptr() };
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if matches!(state.status, Status::Busy) {
+            state.advance_tx(i2c);
+        }
+    });
+}
+
+fn on_rx_done() {
+    let i2c = unsafe { &*I2C::ptr() };
+
+    let result = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !matches!(state.status, Status::Busy) {
+            return None;
+        }
+
+        state.rx_done = true;
+        state.try_finish(i2c)
+    });
+
+    if let Some(result) = result {
+        super::notify(result);
+    }
+}
+
+/// Advance the current DMA transfer, called from `I2C_Handler`
+pub(super) fn on_interrupt() {
+    let i2c = unsafe { &*I2C::ptr() };
+
+    let result = interrupt::free(|cs| STATE.borrow(cs).borrow_mut().service(i2c));
+
+    if let Some(result) = result {
+        super::notify(result);
+    }
+}
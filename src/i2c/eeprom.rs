@@ -0,0 +1,279 @@
+//! Driver for I2C EEPROMs of the 24xx family.
+//!
+//! The driver works on top of any blocking I2C implementation, so it can be
+//! used with [`I2c`](super::I2c) directly or with a device proxy of one of the
+//! shared bus managers.
+//!
+//! Memory addresses which do not fit into the address bytes sent on the bus
+//! are encoded into the lower bits of the device address, as done by parts
+//! like the 24C04/08/16 or 24M01.
+//!
+//! See:
+//! * sdk/sdk/platform/driver/i2c_eeprom/i2c_eeprom.c
+
+use embedded_hal_1::i2c::{self, ErrorKind, I2c, NoAcknowledgeSource, Operation};
+use embedded_storage::{ReadStorage, Storage};
+
+/// Default device address of 24xx EEPROMs with all address pins tied low
+pub const DEFAULT_ADDRESS: u8 = 0x50;
+
+/// Number of bytes used to send the memory address
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MemoryAddressing {
+    OneByte = 1,
+    TwoBytes = 2,
+}
+
+/// Geometry and bus parameters of an EEPROM
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EepromConfig {
+    pub(crate) address: u8,
+    pub(crate) size: u32,
+    pub(crate) page_size: u32,
+    pub(crate) memory_addressing: MemoryAddressing,
+    pub(crate) max_polls: u32,
+}
+
+impl EepromConfig {
+    /// EEPROM of `size` bytes, written in pages of `page_size` bytes
+    pub fn new(size: u32, page_size: u32, memory_addressing: MemoryAddressing) -> Self {
+        assert!(size.is_power_of_two() && page_size.is_power_of_two());
+        assert!(page_size <= size);
+
+        Self {
+            address: DEFAULT_ADDRESS,
+            size,
+            page_size,
+            memory_addressing,
+            max_polls: 1000,
+        }
+    }
+
+    /// 24C02: 256 bytes, 8 byte pages
+    pub fn c02() -> Self {
+        Self::new(256, 8, MemoryAddressing::OneByte)
+    }
+
+    /// 24C16: 2 KiB, 16 byte pages
+    pub fn c16() -> Self {
+        Self::new(2 * 1024, 16, MemoryAddressing::OneByte)
+    }
+
+    /// 24C64: 8 KiB, 32 byte pages
+    pub fn c64() -> Self {
+        Self::new(8 * 1024, 32, MemoryAddressing::TwoBytes)
+    }
+
+    /// 24C256: 32 KiB, 64 byte pages
+    pub fn c256() -> Self {
+        Self::new(32 * 1024, 64, MemoryAddressing::TwoBytes)
+    }
+
+    /// 7-bit device address, defaults to [`DEFAULT_ADDRESS`]
+    pub fn set_address(mut self, address: u8) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Maximum number of ACK polls while waiting for a write cycle to complete
+    pub fn set_max_polls(mut self, max_polls: u32) -> Self {
+        self.max_polls = max_polls;
+        self
+    }
+
+    /// Number of bytes which are addressed by the address bytes alone
+    fn block_size(&self) -> u32 {
+        1 << (8 * self.memory_addressing as u32)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error<E> {
+    /// The I2C bus reported an error
+    I2c(E),
+    /// The access exceeds the size of the EEPROM
+    OutOfBounds,
+    /// The EEPROM did not finish its write cycle in time
+    Timeout,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(error: E) -> Self {
+        Error::I2c(error)
+    }
+}
+
+/// A 24xx EEPROM connected to an I2C bus
+pub struct Eeprom<I2C> {
+    i2c: I2C,
+    config: EepromConfig,
+}
+
+impl<I2C: I2c> Eeprom<I2C> {
+    pub fn new(i2c: I2C, config: EepromConfig) -> Self {
+        Self { i2c, config }
+    }
+
+    /// Release the I2C bus
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    /// Size of the EEPROM in bytes
+    pub fn size(&self) -> u32 {
+        self.config.size
+    }
+
+    /// Read `buffer.len()` bytes starting at `offset`
+    pub fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<(), Error<I2C::Error>> {
+        self.check_bounds(offset, buffer.len())?;
+
+        // Sequential reads wrap around within the memory addressed by the
+        // address bytes, so they must not cross such a block
+        let mut offset = offset;
+        let block_size = self.config.block_size();
+
+        for chunk in chunks_mut(buffer, offset, block_size) {
+            let (address, memory_address) = self.split_address(offset);
+            let memory_address = &memory_address[..self.config.memory_addressing as usize];
+
+            self.i2c.write_read(address, memory_address, chunk)?;
+
+            offset += chunk.len() as u32;
+        }
+
+        Ok(())
+    }
+
+    /// Write `data` starting at `offset`
+    ///
+    /// The data is split at page boundaries and each page write waits for the
+    /// write cycle of the EEPROM to complete.
+    pub fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Error<I2C::Error>> {
+        self.check_bounds(offset, data.len())?;
+
+        let mut offset = offset;
+        let mut data = data;
+
+        while !data.is_empty() {
+            let page_remaining = self.config.page_size - offset % self.config.page_size;
+            let (page, rest) = data.split_at(data.len().min(page_remaining as usize));
+
+            self.write_page(offset, page)?;
+
+            offset += page.len() as u32;
+            data = rest;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self, offset: u32) -> Result<u8, Error<I2C::Error>> {
+        let mut byte = [0];
+        self.read(offset, &mut byte)?;
+        Ok(byte[0])
+    }
+
+    pub fn write_byte(&mut self, offset: u32, byte: u8) -> Result<(), Error<I2C::Error>> {
+        self.write(offset, &[byte])
+    }
+
+    /// Block until the EEPROM acknowledges its address again
+    ///
+    /// The EEPROM does not respond to its address while a write cycle is in
+    /// progress.
+    pub fn wait_ready(&mut self) -> Result<(), Error<I2C::Error>> {
+        let (address, memory_address) = self.split_address(0);
+        let memory_address = &memory_address[..self.config.memory_addressing as usize];
+
+        for _ in 0..self.config.max_polls {
+            // Only loads the address pointer, no write cycle is started
+            match self.i2c.write(address, memory_address) {
+                Ok(()) => return Ok(()),
+                Err(error) if is_address_nack(&error) => continue,
+                Err(error) => return Err(Error::I2c(error)),
+            }
+        }
+
+        Err(Error::Timeout)
+    }
+
+    /// Write data which does not cross a page boundary
+    fn write_page(&mut self, offset: u32, data: &[u8]) -> Result<(), Error<I2C::Error>> {
+        let (address, memory_address) = self.split_address(offset);
+        let memory_address = &memory_address[..self.config.memory_addressing as usize];
+
+        // Adjacent writes are merged into one write on the bus
+        self.i2c.transaction(
+            address,
+            &mut [Operation::Write(memory_address), Operation::Write(data)],
+        )?;
+
+        self.wait_ready()
+    }
+
+    fn check_bounds(&self, offset: u32, len: usize) -> Result<(), Error<I2C::Error>> {
+        match offset.checked_add(len as u32) {
+            Some(end) if end <= self.config.size => Ok(()),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// Split a memory offset into the device address and the address bytes
+    fn split_address(&self, offset: u32) -> (u8, [u8; 2]) {
+        let block = offset / self.config.block_size();
+        let address = self.config.address | block as u8;
+
+        let memory_address = match self.config.memory_addressing {
+            MemoryAddressing::OneByte => [offset as u8, 0],
+            MemoryAddressing::TwoBytes => [(offset >> 8) as u8, offset as u8],
+        };
+
+        (address, memory_address)
+    }
+}
+
+/// Split `buffer` so that no chunk crosses a multiple of `block_size`
+fn chunks_mut(buffer: &mut [u8], offset: u32, block_size: u32) -> impl Iterator<Item = &mut [u8]> {
+    let mut rest = buffer;
+    let mut offset = offset;
+
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let len = rest.len().min((block_size - offset % block_size) as usize);
+        let (chunk, tail) = core::mem::take(&mut rest).split_at_mut(len);
+
+        rest = tail;
+        offset += len as u32;
+
+        Some(chunk)
+    })
+}
+
+fn is_address_nack<E: i2c::Error>(error: &E) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address | NoAcknowledgeSource::Unknown)
+    )
+}
+
+impl<I2C: I2c> ReadStorage for Eeprom<I2C> {
+    type Error = Error<I2C::Error>;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Eeprom::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.config.size as usize
+    }
+}
+
+impl<I2C: I2c> Storage for Eeprom<I2C> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        Eeprom::write(self, offset, bytes)
+    }
+}
@@ -0,0 +1,162 @@
+//! Sharing one I2C bus between several drivers.
+//!
+//! A bus manager takes ownership of the I2C peripheral and hands out device
+//! proxies, each of which implements the I2C traits and can be moved into a
+//! different driver. Every transaction of a proxy runs with exclusive access to
+//! the bus, so transactions from different proxies never interleave.
+//!
+//! * [`RefCellBus`] is meant for drivers which are all used from the same
+//!   execution context. Accessing the bus from two contexts at once panics.
+//! * [`CriticalSectionBus`] runs each transaction within a critical section and
+//!   can be shared with interrupt handlers.
+//!
+//! The critical sections come from the `critical-section` crate. The default
+//! feature `critical-section-single-core` provides its implementation, without
+//! it the application has to provide one or the firmware fails to link.
+
+use core::cell::RefCell;
+
+use critical_section::Mutex;
+use embedded_hal::blocking::i2c as i2c_02;
+use embedded_hal_1::i2c::{self as i2c_1, Operation};
+
+/// Bus manager for use from a single execution context
+pub struct RefCellBus<I2C> {
+    bus: RefCell<I2C>,
+}
+
+impl<I2C> RefCellBus<I2C> {
+    pub const fn new(bus: I2C) -> Self {
+        Self {
+            bus: RefCell::new(bus),
+        }
+    }
+
+    /// Create a proxy for one device on the bus
+    pub fn acquire(&self) -> RefCellDevice<'_, I2C> {
+        RefCellDevice { bus: &self.bus }
+    }
+
+    /// Release the I2C peripheral
+    pub fn into_inner(self) -> I2C {
+        self.bus.into_inner()
+    }
+}
+
+/// Proxy for one device on a [`RefCellBus`]
+pub struct RefCellDevice<'a, I2C> {
+    bus: &'a RefCell<I2C>,
+}
+
+impl<'a, I2C> RefCellDevice<'a, I2C> {
+    fn lock<R>(&self, f: impl FnOnce(&mut I2C) -> R) -> R {
+        f(&mut self.bus.borrow_mut())
+    }
+}
+
+/// Bus manager which can be shared with interrupt handlers
+///
+/// Needs a `critical-section` implementation, provided by the default
+/// feature `critical-section-single-core`.
+pub struct CriticalSectionBus<I2C> {
+    bus: Mutex<RefCell<I2C>>,
+}
+
+impl<I2C> CriticalSectionBus<I2C> {
+    pub const fn new(bus: I2C) -> Self {
+        Self {
+            bus: Mutex::new(RefCell::new(bus)),
+        }
+    }
+
+    /// Create a proxy for one device on the bus
+    pub fn acquire(&self) -> CriticalSectionDevice<'_, I2C> {
+        CriticalSectionDevice { bus: &self.bus }
+    }
+
+    /// Release the I2C peripheral
+    pub fn into_inner(self) -> I2C {
+        self.bus.into_inner().into_inner()
+    }
+}
+
+/// Proxy for one device on a [`CriticalSectionBus`]
+pub struct CriticalSectionDevice<'a, I2C> {
+    bus: &'a Mutex<RefCell<I2C>>,
+}
+
+impl<'a, I2C> CriticalSectionDevice<'a, I2C> {
+    fn lock<R>(&self, f: impl FnOnce(&mut I2C) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.bus.borrow_ref_mut(cs)))
+    }
+}
+
+macro_rules! shared_device {
+    ($($device:ident,)+) => {
+        $(
+            impl<'a, I2C> i2c_1::ErrorType for $device<'a, I2C>
+            where
+                I2C: i2c_1::ErrorType,
+            {
+                type Error = I2C::Error;
+            }
+
+            impl<'a, I2C, A> i2c_1::I2c<A> for $device<'a, I2C>
+            where
+                I2C: i2c_1::I2c<A>,
+                A: i2c_1::AddressMode,
+            {
+                fn transaction(
+                    &mut self,
+                    address: A,
+                    operations: &mut [Operation<'_>],
+                ) -> Result<(), Self::Error> {
+                    self.lock(|bus| bus.transaction(address, operations))
+                }
+            }
+
+            impl<'a, I2C, A> i2c_02::Write<A> for $device<'a, I2C>
+            where
+                I2C: i2c_02::Write<A>,
+                A: i2c_02::AddressMode,
+            {
+                type Error = I2C::Error;
+
+                fn write(&mut self, address: A, bytes: &[u8]) -> Result<(), Self::Error> {
+                    self.lock(|bus| bus.write(address, bytes))
+                }
+            }
+
+            impl<'a, I2C, A> i2c_02::Read<A> for $device<'a, I2C>
+            where
+                I2C: i2c_02::Read<A>,
+                A: i2c_02::AddressMode,
+            {
+                type Error = I2C::Error;
+
+                fn read(&mut self, address: A, buffer: &mut [u8]) -> Result<(), Self::Error> {
+                    self.lock(|bus| bus.read(address, buffer))
+                }
+            }
+
+            impl<'a, I2C, A> i2c_02::WriteRead<A> for $device<'a, I2C>
+            where
+                I2C: i2c_02::WriteRead<A>,
+                A: i2c_02::AddressMode,
+            {
+                type Error = I2C::Error;
+
+                fn write_read(
+                    &mut self,
+                    address: A,
+                    bytes: &[u8],
+                    buffer: &mut [u8],
+                ) -> Result<(), Self::Error> {
+                    self.lock(|bus| bus.write_read(address, bytes, buffer))
+                }
+            }
+        )+
+    };
+}
+
+shared_device!(RefCellDevice, CriticalSectionDevice,);
@@ -0,0 +1,307 @@
+//! Interrupt driven, non-blocking I2C transfers.
+//!
+//! A transfer is queued with [`I2c::start_write`], [`I2c::start_read`] or
+//! [`I2c::start_write_read`]. From then on `I2C_Handler` feeds the TX FIFO with
+//! data and read commands and drains the RX FIFO, so the CPU is free to sleep
+//! until the transfer has finished.
+
+use core::{cell::RefCell, ptr};
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    pac::{i2c::RegisterBlock, I2C},
+};
+
+use super::{AddressingMode, Error, I2c};
+
+/// Depth of the TX and RX FIFOs
+pub(crate) const FIFO_DEPTH: usize = 4;
+
+/// TX FIFO level at (or below) which the TX_EMPTY interrupt fires
+const TX_THRESHOLD: u8 = (FIFO_DEPTH / 2) as u8;
+
+#[derive(Clone, Copy)]
+pub(super) enum Status {
+    Idle,
+    Busy,
+    Done(Result<(), Error>),
+}
+
+struct State {
+    tx: *const u8,
+    tx_len: usize,
+    rx: *mut u8,
+    rx_len: usize,
+    /// Number of data bytes and read commands pushed into the TX FIFO
+    issued: usize,
+    /// Number of bytes drained from the RX FIFO
+    received: usize,
+    status: Status,
+}
+
+// The buffer pointers are only dereferenced within a critical section while the
+// owning `Transfer` keeps the buffers alive.
+unsafe impl Send for State {}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl Status {
+    pub(super) fn poll(self) -> nb::Result<(), Error> {
+        match self {
+            Status::Done(Ok(())) => Ok(()),
+            Status::Done(Err(error)) => Err(nb::Error::Other(error)),
+            Status::Idle | Status::Busy => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            tx: ptr::null(),
+            tx_len: 0,
+            rx: ptr::null_mut(),
+            rx_len: 0,
+            issued: 0,
+            received: 0,
+            status: Status::Idle,
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.tx_len + self.rx_len
+    }
+
+    /// Number of read commands which have been issued but not yet drained
+    fn outstanding_reads(&self) -> usize {
+        self.issued.saturating_sub(self.tx_len) - self.received
+    }
+
+    fn fill_tx_fifo(&mut self, i2c: &RegisterBlock) {
+        let total = self.total();
+
+        while self.issued < total && i2c.i2c_status_reg.read().tfnf().bit_is_set() {
+            let stop = self.issued + 1 == total;
+
+            if self.issued < self.tx_len {
+                let byte = unsafe { *self.tx.add(self.issued) };
+
+                i2c.i2c_data_cmd_reg.write(|w| {
+                    w.i2c_cmd().clear_bit();
+                    w.i2c_stop().bit(stop);
+                    unsafe { w.dat().bits(byte) }
+                });
+            } else {
+                // Never request more bytes than the RX FIFO is able to hold
+                if self.outstanding_reads() >= FIFO_DEPTH {
+                    break;
+                }
+
+                // Turn the bus around with a repeated START after the written bytes
+                let restart = self.tx_len > 0 && self.issued == self.tx_len;
+
+                i2c.i2c_data_cmd_reg.write(|w| {
+                    w.i2c_cmd().set_bit();
+                    w.i2c_restart().bit(restart);
+                    w.i2c_stop().bit(stop)
+                });
+            }
+
+            self.issued += 1;
+        }
+
+        if self.issued == total {
+            i2c.i2c_intr_mask_reg
+                .modify(|_, w| w.m_tx_empty().clear_bit());
+        }
+    }
+
+    fn drain_rx_fifo(&mut self, i2c: &RegisterBlock) {
+        while self.received < self.rx_len && i2c.i2c_status_reg.read().rfne().bit_is_set() {
+            let byte = i2c.i2c_data_cmd_reg.read().dat().bits();
+            unsafe { *self.rx.add(self.received) = byte };
+            self.received += 1;
+        }
+    }
+
+    /// Raise the RX_FULL interrupt once all outstanding read commands have been answered
+    fn update_rx_threshold(&self, i2c: &RegisterBlock) {
+        let level = self.outstanding_reads().max(1) - 1;
+
+        i2c.i2c_rx_tl_reg
+            .write(|w| unsafe { w.rx_tl().bits(level as u8) });
+    }
+
+    fn service(&mut self, i2c: &RegisterBlock) -> Option<Result<(), Error>> {
+        if !matches!(self.status, Status::Busy) {
+            return None;
+        }
+
+        let stat = i2c.i2c_intr_stat_reg.read();
+
+        if let Some(error) = super::abort_error(i2c) {
+            return Some(self.finish(i2c, Err(error)));
+        }
+
+        if stat.r_rx_over().bit_is_set() {
+            return Some(self.finish(i2c, Err(Error::Receive)));
+        }
+
+        self.drain_rx_fifo(i2c);
+        self.fill_tx_fifo(i2c);
+        self.update_rx_threshold(i2c);
+
+        if stat.r_stop_det().bit_is_set() {
+            i2c.i2c_clr_stop_det_reg.read();
+
+            if self.issued == self.total() {
+                self.drain_rx_fifo(i2c);
+
+                let result = if self.received == self.rx_len {
+                    Ok(())
+                } else {
+                    Err(Error::Receive)
+                };
+
+                return Some(self.finish(i2c, result));
+            }
+        }
+
+        None
+    }
+
+    fn finish(&mut self, i2c: &RegisterBlock, result: Result<(), Error>) -> Result<(), Error> {
+        i2c.i2c_intr_mask_reg.write(|w| unsafe { w.bits(0) });
+
+        // Discard whatever is left over from an aborted transfer
+        while i2c.i2c_status_reg.read().rfne().bit_is_set() {
+            i2c.i2c_data_cmd_reg.read();
+        }
+
+        // Clears all individual interrupts and the abort source
+        i2c.i2c_clr_intr_reg.read();
+
+        self.status = Status::Done(result);
+        result
+    }
+}
+
+/// A non-blocking I2C transfer driven by the I2C interrupt
+///
+/// The transfer owns the I2C peripheral and the buffers until it has finished,
+/// both are handed back by [`Transfer::wait`].
+pub struct Transfer<B> {
+    i2c: I2c,
+    buffer: B,
+}
+
+impl<B> Transfer<B> {
+    fn start(
+        mut i2c: I2c,
+        buffer: B,
+        address: u8,
+        (tx, tx_len): (*const u8, usize),
+        (rx, rx_len): (*mut u8, usize),
+    ) -> Self {
+        i2c.set_target(address as u16, AddressingMode::Bits7);
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            *state = State {
+                tx,
+                tx_len,
+                rx,
+                rx_len,
+                issued: 0,
+                received: 0,
+                status: Status::Busy,
+            };
+
+            if state.total() == 0 {
+                state.status = Status::Done(Ok(()));
+                return;
+            }
+
+            let regs = &i2c.i2c;
+
+            regs.i2c_clr_intr_reg.read();
+            regs.i2c_tx_tl_reg
+                .write(|w| unsafe { w.rx_tl().bits(TX_THRESHOLD) });
+            state.update_rx_threshold(regs);
+
+            // The TX_EMPTY interrupt fires right away and fills the TX FIFO
+            regs.i2c_intr_mask_reg.write(|w| {
+                w.m_tx_empty().set_bit();
+                w.m_tx_abrt().set_bit();
+                w.m_stop_det().set_bit();
+                w.m_rx_over().set_bit();
+                w.m_rx_full().bit(rx_len > 0)
+            });
+        });
+
+        Self { i2c, buffer }
+    }
+
+    /// Check whether the transfer has finished, either successfully or with an error
+    pub fn is_done(&self) -> bool {
+        !matches!(self.poll(), Err(nb::Error::WouldBlock))
+    }
+
+    /// Poll the result of the transfer
+    ///
+    /// Returns `nb::Error::WouldBlock` while the transfer is still in progress.
+    pub fn poll(&self) -> nb::Result<(), Error> {
+        interrupt::free(|cs| STATE.borrow(cs).borrow().status.poll())
+    }
+
+    /// Block until the transfer has finished
+    ///
+    /// Returns the result of the transfer together with the I2C peripheral and
+    /// the buffers.
+    pub fn wait(self) -> (Result<(), Error>, I2c, B) {
+        let result = nb::block!(self.poll());
+
+        interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = State::new());
+
+        (result, self.i2c, self.buffer)
+    }
+}
+
+impl I2c {
+    /// Queue a write of `buffer` to the slave at `address`
+    pub fn start_write(self, address: u8, buffer: &'static [u8]) -> Transfer<&'static [u8]> {
+        let tx = (buffer.as_ptr(), buffer.len());
+        Transfer::start(self, buffer, address, tx, (ptr::null_mut(), 0))
+    }
+
+    /// Queue a read from the slave at `address` into `buffer`
+    pub fn start_read(self, address: u8, buffer: &'static mut [u8]) -> Transfer<&'static mut [u8]> {
+        let rx = (buffer.as_mut_ptr(), buffer.len());
+        Transfer::start(self, buffer, address, (ptr::null(), 0), rx)
+    }
+
+    /// Queue a write of `wr_buffer` followed by a read into `rd_buffer` from the
+    /// slave at `address`
+    pub fn start_write_read(
+        self,
+        address: u8,
+        wr_buffer: &'static [u8],
+        rd_buffer: &'static mut [u8],
+    ) -> Transfer<(&'static [u8], &'static mut [u8])> {
+        let tx = (wr_buffer.as_ptr(), wr_buffer.len());
+        let rx = (rd_buffer.as_mut_ptr(), rd_buffer.len());
+        Transfer::start(self, (wr_buffer, rd_buffer), address, tx, rx)
+    }
+}
+
+/// Advance the current transfer, called from `I2C_Handler`
+pub(super) fn on_interrupt() {
+    let i2c = unsafe { &*I2C::ptr() };
+
+    let result = interrupt::free(|cs| STATE.borrow(cs).borrow_mut().service(i2c));
+
+    if let Some(result) = result {
+        super::notify(result);
+    }
+}
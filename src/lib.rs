@@ -1,14 +1,25 @@
-#![no_std]
+//! HAL for the DA14531.
+//!
+//! The default feature `critical-section-single-core` provides the
+//! `critical-section` implementation of `cortex-m`, which the shared I2C bus
+//! relies on. Disable it to link a different implementation.
 
+#![cfg_attr(not(test), no_std)]
+
+pub mod battery;
 pub mod crg_aon;
 pub mod crg_top;
+pub mod delay;
+pub mod dma;
 pub mod gpadc;
 pub mod gpio;
 pub mod i2c;
 pub mod nvic;
 pub mod otpc;
 pub mod sys_wdog;
+pub mod time;
 pub mod timer;
+pub mod timer1;
 pub mod wkup;
 
 pub use cortex_m as cm;
@@ -78,6 +78,10 @@ pub enum Irq {
     /// Combines the Wake up Capture Timer Interrupt Request,
     /// the GPIO Interrupt and the QuadDecoder Interrupt Request.
     WakupQuadec = 16,
+    /// Timer1 Interrupt Request.
+    SwTim1 = 17,
+    /// DMA Interrupt Request.
+    Dma = 19,
 }
 
 unsafe impl InterruptNumber for Irq {
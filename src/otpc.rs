@@ -6,6 +6,45 @@ use config::OtpcConfig;
 
 use self::config::Mode;
 
+/// Start of the OTP in the memory map, readable in [`Mode::Read`]
+const OTP_BASE: usize = 0x07f8_0000;
+
+/// Size of the OTP in bytes
+const OTP_SIZE: usize = 32 * 1024;
+
+/// Offset of the configuration script, it fills the end of the OTP
+const CS_OFFSET: usize = 0x7ed0;
+
+const CS_START: u32 = 0xa5a5_a5a5;
+const CS_STOP: u32 = 0x0000_0000;
+const CS_EMPTY: u32 = 0xffff_ffff;
+
+/// Command of an entry of the configuration script, in the upper 4 bits
+const CS_CMD_MASK: u32 = 0xf000_0000;
+/// Register write, the address is followed by the value
+const CS_CMD_REGISTER: u32 = 0x5000_0000;
+/// Value stored for the SDK, the command is followed by the value
+const CS_CMD_SDK_VALUE: u32 = 0x9000_0000;
+
+/// Values stored for the SDK in the configuration script during production
+/// test, identified by the lowest byte of their command
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum SdkValue {
+    /// Trim value of the GPADC LDO
+    AdcTrim = 0x01,
+    /// GPADC gain error in single ended mode
+    AdcSingleEndedGainError = 0x02,
+    /// GPADC offset in single ended mode
+    AdcSingleEndedOffset = 0x03,
+    /// GPADC gain error in differential mode
+    AdcDifferentialGainError = 0x04,
+    /// GPADC offset in differential mode
+    AdcDifferentialOffset = 0x05,
+    /// GPADC sample of the temperature sensor at 25 degrees Celsius
+    AdcTemperature25C = 0x06,
+}
+
 /// Extension trait that constrains the `OTPC` peripheral
 pub trait OtpcExt {
     /// Constrains the `OTPC` peripheral so it plays nicely with the other abstractions
@@ -30,6 +69,50 @@ impl Otpc {
             .write(|w| unsafe { w.otpc_mode_mode().bits(otpc_config.mode as u8) })
     }
 
+    /// Read a word of the OTP, `offset` is in bytes
+    ///
+    /// The OTPC must be enabled in [`Mode::Read`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `offset` is not word aligned or beyond the end of the OTP.
+    pub fn read_word(&self, offset: usize) -> u32 {
+        assert!(offset % 4 == 0 && offset < OTP_SIZE);
+
+        unsafe { core::ptr::read_volatile((OTP_BASE + offset) as *const u32) }
+    }
+
+    /// Look up a value of the configuration script
+    ///
+    /// Returns `None` if the script is not programmed or does not contain the
+    /// value. The OTPC must be enabled in [`Mode::Read`].
+    pub fn sdk_value(&self, value: SdkValue) -> Option<u32> {
+        if self.read_word(CS_OFFSET) != CS_START {
+            return None;
+        }
+
+        let mut offset = CS_OFFSET + 4;
+
+        while offset < OTP_SIZE {
+            let word = self.read_word(offset);
+            offset += 4;
+
+            match word & CS_CMD_MASK {
+                _ if word == CS_STOP || word == CS_EMPTY => return None,
+                CS_CMD_SDK_VALUE if offset < OTP_SIZE => {
+                    if word & 0xff == value as u32 {
+                        return Some(self.read_word(offset));
+                    }
+                    offset += 4;
+                }
+                CS_CMD_REGISTER => offset += 4,
+                _ => {}
+            }
+        }
+
+        None
+    }
+
     pub fn disable(&self, crg_top: &mut CrgTop) {
         self.otpc
             .otpc_mode_reg
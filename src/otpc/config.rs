@@ -20,3 +20,10 @@ impl Default for Mode {
 pub struct OtpcConfig {
     pub(crate) mode: Mode,
 }
+
+impl OtpcConfig {
+    pub fn set_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
@@ -0,0 +1,9 @@
+//! Time units
+//!
+//! Frequencies and durations are expressed with the [`fugit`] types, e.g.
+//! `1.kHz()` or `10.millis()`.
+
+pub use fugit::{
+    ExtU32, HertzU32 as Hertz, MicrosDurationU32 as MicroSeconds,
+    MillisDurationU32 as MilliSeconds, RateExtU32,
+};
@@ -1,3 +1,14 @@
+pub mod count_down;
+pub mod led;
+pub mod pwm;
+pub mod timer2;
+pub mod tone;
+
+pub use count_down::CountDownTimer;
+pub use pwm::Timer0Pwm;
+pub use timer2::Timer2;
+pub use tone::Tone;
+
 use crate::{
     nvic::{Irq, Nvic},
     pac::{CRG_TOP, TIMER0},
@@ -10,12 +21,20 @@ const LOW_POWER_CLOCK_FREQ: u32 = 32_000;
 pub trait Timer0Ext {
     /// Constrains the `TIMER0` peripheral so it plays nicely with the other abstractions
     fn constrain(self) -> Timer0;
+
+    /// Splits the `TIMER0` peripheral into Timer0 and the triple PWM Timer2
+    fn split(self) -> (Timer0, Timer2, timer2::Channels);
 }
 
 impl Timer0Ext for TIMER0 {
     fn constrain(self) -> Timer0 {
         Timer0 { timer: self }
     }
+
+    fn split(self) -> (Timer0, Timer2, timer2::Channels) {
+        let (timer2, channels) = Timer2::new();
+        (Timer0 { timer: self }, timer2, channels)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -48,6 +67,7 @@ pub enum TimerClockDiv {
     Div10 = 0,
 }
 
+#[deprecated(since = "0.2.3", note = "use the typed channels of `Timer0Ext::split`")]
 pub enum Timer2PwmChannel {
     Pwm2,
     Pwm3,
@@ -140,89 +160,54 @@ impl Timer0 {
         }
     }
 
+    #[deprecated(since = "0.2.3", note = "use `Timer2::init` of `Timer0Ext::split`")]
     pub fn init_triple_pwm(&mut self, clk_sel: ClockSel, freq_hz: u32) {
-        let pwm_freq = match clk_sel {
-            ClockSel::SystemClock => ((SYSTEM_CLOCK_FREQ / freq_hz) - 1) as u16,
-            ClockSel::LowPowerClock => ((LOW_POWER_CLOCK_FREQ / freq_hz) - 1) as u16,
+        let ticks = match clk_sel {
+            ClockSel::SystemClock => SYSTEM_CLOCK_FREQ / freq_hz,
+            ClockSel::LowPowerClock => LOW_POWER_CLOCK_FREQ / freq_hz,
         };
 
-        self.timer
-            .triple_pwm_ctrl_reg
-            .modify(|_, w| w.triple_pwm_clk_sel().bit(clk_sel as u8 == 1));
-
-        self.timer
-            .triple_pwm_frequency
-            .modify(|_, w| unsafe { w.pwm_freq().bits(pwm_freq) })
+        timer2::set_period(clk_sel, ticks as u16);
     }
 
+    #[deprecated(since = "0.2.3", note = "use `Timer2::start` of `Timer0Ext::split`")]
     pub fn start_triple_pwm(&mut self) {
-        self.timer
-            .triple_pwm_ctrl_reg
-            .modify(|_, w| w.triple_pwm_enable().set_bit());
+        timer2::set_enabled(true);
     }
 
+    #[deprecated(since = "0.2.3", note = "use `Timer2::stop` of `Timer0Ext::split`")]
     pub fn stop_triple_pwm(&mut self) {
-        self.timer
-            .triple_pwm_ctrl_reg
-            .modify(|_, w| w.triple_pwm_enable().clear_bit());
+        timer2::set_enabled(false);
     }
 
+    #[deprecated(since = "0.2.3", note = "use the typed channels of `Timer0Ext::split`")]
+    #[allow(deprecated)]
     pub fn set_triple_pwm_duty_cycle(&mut self, channel: Timer2PwmChannel, start: u16, end: u16) {
-        match channel {
-            Timer2PwmChannel::Pwm2 => {
-                self.timer
-                    .pwm2_start_cycle
-                    .write(|w| unsafe { w.start_cycle().bits(start) });
-                self.timer
-                    .pwm2_end_cycle
-                    .write(|w| unsafe { w.end_cycle().bits(end) });
-            }
-            Timer2PwmChannel::Pwm3 => {
-                self.timer
-                    .pwm3_start_cycle
-                    .write(|w| unsafe { w.start_cycle().bits(start) });
-                self.timer
-                    .pwm3_end_cycle
-                    .write(|w| unsafe { w.end_cycle().bits(end) });
-            }
-            Timer2PwmChannel::Pwm4 => {
-                self.timer
-                    .pwm4_start_cycle
-                    .write(|w| unsafe { w.start_cycle().bits(start) });
-                self.timer
-                    .pwm4_end_cycle
-                    .write(|w| unsafe { w.end_cycle().bits(end) });
-            }
-            Timer2PwmChannel::Pwm5 => {
-                self.timer
-                    .pwm5_start_cycle
-                    .write(|w| unsafe { w.start_cycle().bits(start) });
-                self.timer
-                    .pwm5_end_cycle
-                    .write(|w| unsafe { w.end_cycle().bits(end) });
-            }
-            Timer2PwmChannel::Pwm6 => {
-                self.timer
-                    .pwm6_start_cycle
-                    .write(|w| unsafe { w.start_cycle().bits(start) });
-                self.timer
-                    .pwm6_end_cycle
-                    .write(|w| unsafe { w.end_cycle().bits(end) });
-            }
-            Timer2PwmChannel::Pwm7 => {
-                self.timer
-                    .pwm7_start_cycle
-                    .write(|w| unsafe { w.start_cycle().bits(start) });
-                self.timer
-                    .pwm7_end_cycle
-                    .write(|w| unsafe { w.end_cycle().bits(end) });
-            }
-        }
+        let index = match channel {
+            Timer2PwmChannel::Pwm2 => 2,
+            Timer2PwmChannel::Pwm3 => 3,
+            Timer2PwmChannel::Pwm4 => 4,
+            Timer2PwmChannel::Pwm5 => 5,
+            Timer2PwmChannel::Pwm6 => 6,
+            Timer2PwmChannel::Pwm7 => 7,
+        };
+
+        timer2::set_cycles(index, start, end);
     }
 }
 
+/// Timer0 interrupt handler
+///
+/// # Safety
+///
+/// Must only be invoked by the NVIC.
 #[no_mangle]
 pub unsafe extern "C" fn SWTIM_Handler() {
+    if count_down::on_interrupt() {
+        led::on_interrupt();
+    }
+    tone::on_interrupt();
+
     if let Some(handler) = TIMER0_HANDLER {
         handler();
     }
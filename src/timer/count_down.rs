@@ -0,0 +1,287 @@
+//! Timer0 as count down timer.
+//!
+//! The ON-counter of Timer0 counts down from `TIMER0_ON_REG` to zero, raises
+//! the SWTIM interrupt and reloads. Timeouts longer than one ON-counter period
+//! at the slowest timer clock are split into several periods which are counted
+//! in the interrupt.
+
+use core::cell::RefCell;
+
+use void::Void;
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    crg_top::Clocks,
+    hal,
+    nvic::{Irq, Nvic},
+    pac::TIMER0,
+    time::MicroSeconds,
+};
+
+use super::{BaseClockDiv, ClockSel, Timer0, TimerClockDiv};
+
+/// Dividers of the system clock, ordered by total division
+const PRESCALERS: [(BaseClockDiv, TimerClockDiv, u32); 8] = [
+    (BaseClockDiv::Div1, TimerClockDiv::Off, 1),
+    (BaseClockDiv::Div2, TimerClockDiv::Off, 2),
+    (BaseClockDiv::Div4, TimerClockDiv::Off, 4),
+    (BaseClockDiv::Div8, TimerClockDiv::Off, 8),
+    (BaseClockDiv::Div1, TimerClockDiv::Div10, 10),
+    (BaseClockDiv::Div2, TimerClockDiv::Div10, 20),
+    (BaseClockDiv::Div4, TimerClockDiv::Div10, 40),
+    (BaseClockDiv::Div8, TimerClockDiv::Div10, 80),
+];
+
+/// Longest ON-counter period in timer clock ticks
+const MAX_TICKS: u64 = u16::MAX as u64 + 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The timer has not been started
+    NotRunning,
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    running: bool,
+    one_shot: bool,
+    expired: bool,
+    /// ON-counter periods per timeout
+    periods: u32,
+    /// ON-counter periods left until the timeout expires
+    remaining: u32,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            one_shot: false,
+            expired: false,
+            periods: 0,
+            remaining: 0,
+        }
+    }
+}
+
+/// Timer clock and ON-counter configuration for one timeout
+struct Timing {
+    clk_sel: ClockSel,
+    base_div: BaseClockDiv,
+    timer_div: TimerClockDiv,
+    /// ON-counter period in timer clock ticks
+    ticks: u32,
+    periods: u32,
+}
+
+impl Timing {
+    fn new(clocks: &Clocks, timeout: MicroSeconds) -> Self {
+        let us = timeout.ticks() as u64;
+        let ticks_at = |clock: u32| (us * clock as u64 / 1_000_000).max(1);
+
+        let sys_clk = clocks.sys_clk().raw();
+
+        for &(base_div, timer_div, div) in PRESCALERS.iter() {
+            let ticks = ticks_at(sys_clk / div);
+
+            if ticks <= MAX_TICKS {
+                return Self {
+                    clk_sel: ClockSel::SystemClock,
+                    base_div,
+                    timer_div,
+                    ticks: ticks as u32,
+                    periods: 1,
+                };
+            }
+        }
+
+        // Long timeouts run from the low power clock, split into several
+        // ON-counter periods if necessary
+        let lp_clk = clocks.lp_clk().raw();
+
+        let (timer_div, ticks) = match ticks_at(lp_clk) {
+            ticks if ticks <= MAX_TICKS => (TimerClockDiv::Off, ticks),
+            _ => (TimerClockDiv::Div10, ticks_at(lp_clk / 10)),
+        };
+
+        let periods = (ticks + MAX_TICKS - 1) / MAX_TICKS;
+
+        Self {
+            clk_sel: ClockSel::LowPowerClock,
+            base_div: BaseClockDiv::Div1,
+            timer_div,
+            ticks: (ticks / periods) as u32,
+            periods: periods as u32,
+        }
+    }
+}
+
+/// Timer0 used as count down timer
+pub struct CountDownTimer {
+    timer: Timer0,
+    clocks: Clocks,
+}
+
+impl Timer0 {
+    /// Use Timer0 as count down timer and enable the SWTIM interrupt
+    pub fn into_count_down(mut self, clocks: &Clocks, nvic: &mut Nvic) -> CountDownTimer {
+        self.stop();
+        self.enable_clock();
+
+        nvic.set_priority(Irq::SwTim0, 2);
+        nvic.enable_irq(Irq::SwTim0);
+
+        CountDownTimer {
+            timer: self,
+            clocks: *clocks,
+        }
+    }
+}
+
+impl CountDownTimer {
+    /// Start a periodic timeout
+    ///
+    /// A running timeout is restarted.
+    pub fn start(&mut self, timeout: MicroSeconds) {
+        self.start_timer(timeout, false);
+    }
+
+    /// Start a timeout which expires once, the timer stops itself in the
+    /// interrupt
+    pub fn start_one_shot(&mut self, timeout: MicroSeconds) {
+        self.start_timer(timeout, true);
+    }
+
+    /// Check whether the timeout has expired since the last call
+    ///
+    /// Returns `nb::Error::WouldBlock` until then.
+    pub fn wait(&mut self) -> nb::Result<(), Void> {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            if state.expired {
+                state.expired = false;
+                Ok(())
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        })
+    }
+
+    /// Check whether a timeout is pending
+    pub fn is_running(&self) -> bool {
+        interrupt::free(|cs| STATE.borrow(cs).borrow().running)
+    }
+
+    /// Stop the timer
+    pub fn cancel(&mut self) -> Result<(), Error> {
+        let running = interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            let running = state.running;
+
+            *state = State::new();
+            running
+        });
+
+        self.timer.stop();
+
+        if running {
+            Ok(())
+        } else {
+            Err(Error::NotRunning)
+        }
+    }
+
+    /// Release Timer0
+    pub fn free(mut self) -> Timer0 {
+        self.cancel().ok();
+        self.timer
+    }
+
+    fn start_timer(&mut self, timeout: MicroSeconds, one_shot: bool) {
+        let timing = Timing::new(&self.clocks, timeout);
+
+        // Stopping the timer also resets the ON-counter
+        self.timer.stop();
+
+        self.timer.set_clock_div(timing.base_div);
+        self.timer.timer.timer0_ctrl_reg.modify(|_, w| {
+            w.tim0_clk_sel().bit(timing.clk_sel as u8 == 1);
+            w.tim0_clk_div().bit(timing.timer_div as u8 == 1);
+            w
+        });
+        self.timer.set_pwm_on((timing.ticks - 1) as u16);
+
+        interrupt::free(|cs| {
+            *STATE.borrow(cs).borrow_mut() = State {
+                running: true,
+                one_shot,
+                expired: false,
+                periods: timing.periods,
+                remaining: timing.periods,
+            };
+        });
+
+        self.timer.start();
+    }
+}
+
+impl hal::timer::CountDown for CountDownTimer {
+    type Time = MicroSeconds;
+
+    fn start<T>(&mut self, count: T)
+    where
+        T: Into<Self::Time>,
+    {
+        CountDownTimer::start(self, count.into());
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        CountDownTimer::wait(self)
+    }
+}
+
+impl hal::timer::Periodic for CountDownTimer {}
+
+impl hal::timer::Cancel for CountDownTimer {
+    type Error = Error;
+
+    fn cancel(&mut self) -> Result<(), Self::Error> {
+        CountDownTimer::cancel(self)
+    }
+}
+
+/// Count the elapsed ON-counter period, called from `SWTIM_Handler`
+///
+/// Returns whether the timeout has expired.
+pub(super) fn on_interrupt() -> bool {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !state.running {
+            return false;
+        }
+
+        state.remaining -= 1;
+        if state.remaining > 0 {
+            return false;
+        }
+
+        state.expired = true;
+
+        if state.one_shot {
+            state.running = false;
+
+            let timer = unsafe { &*TIMER0::ptr() };
+            timer
+                .timer0_ctrl_reg
+                .modify(|_, w| w.tim0_ctrl().clear_bit());
+        } else {
+            state.remaining = state.periods;
+        }
+
+        true
+    })
+}
@@ -0,0 +1,476 @@
+//! LED effects on PWM channels.
+//!
+//! An [`Led`] drives one PWM channel, an [`RgbLed`] mixes a color on three
+//! channels, typically the Timer2 outputs PWM2 to PWM7. Both play an
+//! [`Effect`]: a constant level, a fade, breathing or a blink pattern.
+//!
+//! The effects are advanced by calling `tick` with the elapsed time. A
+//! [`LedTimer`] does this in the background: it runs the
+//! [`CountDownTimer`] periodically and `SWTIM_Handler` ticks the attached
+//! LEDs whenever the period has elapsed. Levels are mapped to duty cycles
+//! either linearly or gamma corrected, which makes fades look even to the eye.
+
+use core::cell::RefCell;
+
+use crate::{
+    cm::interrupt::{self, CriticalSection, Mutex},
+    hal::PwmPin,
+    time::{MicroSeconds, MilliSeconds},
+};
+
+use super::CountDownTimer;
+
+/// Largest number of LEDs attached to a [`LedTimer`]
+pub const MAX_LEDS: usize = 4;
+
+/// Mapping of a level to a duty cycle
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Curve {
+    Linear,
+    /// Perceptually even brightness steps, gamma of about 2.5
+    Gamma,
+}
+
+impl Curve {
+    /// Map a level to a fraction of the full duty cycle, scaled to `u16::MAX`
+    fn apply(self, level: u8) -> u16 {
+        let x = level as u64;
+
+        match self {
+            Curve::Linear => (x * 0x101) as u16,
+            // Average of gamma 2 and gamma 3
+            Curve::Gamma => ((x * x * 255 + x * x * x) * 0xffff / (2 * 255 * 255 * 255)) as u16,
+        }
+    }
+}
+
+/// Color of an RGB LED
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const OFF: Color = Color::new(0, 0, 0);
+    pub const WHITE: Color = Color::new(255, 255, 255);
+    pub const RED: Color = Color::new(255, 0, 0);
+    pub const GREEN: Color = Color::new(0, 255, 0);
+    pub const BLUE: Color = Color::new(0, 0, 255);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Scale the color by `level` / 255
+    pub fn dim(self, level: u8) -> Self {
+        let scale = |c: u8| (c as u16 * level as u16 / 255) as u8;
+        Self::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+}
+
+/// Brightness of an LED, a single level or a color
+pub trait Level: Copy {
+    /// Interpolate between `from` and `to`, `t` ranges from 0 to `u16::MAX`
+    fn mix(from: Self, to: Self, t: u16) -> Self;
+}
+
+impl Level for u8 {
+    fn mix(from: Self, to: Self, t: u16) -> Self {
+        let (from, to, t) = (from as i32, to as i32, t as i32);
+        (from + (to - from) * t / u16::MAX as i32) as u8
+    }
+}
+
+impl Level for Color {
+    fn mix(from: Self, to: Self, t: u16) -> Self {
+        Self::new(
+            u8::mix(from.r, to.r, t),
+            u8::mix(from.g, to.g, t),
+            u8::mix(from.b, to.b, t),
+        )
+    }
+}
+
+/// One step of a blink pattern
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Step<T> {
+    pub level: T,
+    pub duration_ms: u32,
+}
+
+impl<T> Step<T> {
+    pub const fn new(level: T, duration_ms: u32) -> Self {
+        Self { level, duration_ms }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Effect<T: 'static> {
+    /// Constant level
+    Solid(T),
+    /// Fade from one level to another and stay there
+    Fade { from: T, to: T, duration_ms: u32 },
+    /// Fade between two levels back and forth
+    Breathe { low: T, high: T, period_ms: u32 },
+    /// Step through the levels of a pattern, once or repeatedly
+    Blink {
+        pattern: &'static [Step<T>],
+        repeat: bool,
+    },
+}
+
+/// Playback state of an effect
+#[derive(Clone, Copy)]
+struct Animation<T: 'static> {
+    effect: Effect<T>,
+    elapsed_ms: u32,
+}
+
+impl<T: Level> Animation<T> {
+    fn new(effect: Effect<T>) -> Self {
+        Self {
+            effect,
+            elapsed_ms: 0,
+        }
+    }
+
+    /// Length of one cycle of a repeating effect
+    fn cycle_ms(&self) -> Option<u32> {
+        match self.effect {
+            Effect::Breathe { period_ms, .. } => Some(period_ms),
+            Effect::Blink {
+                pattern,
+                repeat: true,
+            } => Some(pattern.iter().map(|step| step.duration_ms).sum()),
+            _ => None,
+        }
+    }
+
+    fn advance(&mut self, elapsed_ms: u32) {
+        self.elapsed_ms = self.elapsed_ms.saturating_add(elapsed_ms);
+
+        if let Some(cycle_ms) = self.cycle_ms().filter(|&cycle_ms| cycle_ms > 0) {
+            self.elapsed_ms %= cycle_ms;
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        match self.effect {
+            Effect::Solid(_) | Effect::Breathe { .. } => false,
+            Effect::Fade { duration_ms, .. } => self.elapsed_ms >= duration_ms,
+            Effect::Blink { pattern, repeat } => {
+                !repeat && self.elapsed_ms >= pattern.iter().map(|step| step.duration_ms).sum()
+            }
+        }
+    }
+
+    fn level(&self) -> Option<T> {
+        let t = self.elapsed_ms;
+
+        match self.effect {
+            Effect::Solid(level) => Some(level),
+            Effect::Fade {
+                from,
+                to,
+                duration_ms,
+            } => Some(T::mix(from, to, fraction(t, duration_ms))),
+            Effect::Breathe {
+                low,
+                high,
+                period_ms,
+            } => {
+                let half = (period_ms / 2).max(1);
+                let t = if t < half {
+                    fraction(t, half)
+                } else {
+                    u16::MAX - fraction(t - half, half)
+                };
+
+                Some(T::mix(low, high, t))
+            }
+            Effect::Blink { pattern, .. } => {
+                let mut start = 0;
+
+                for step in pattern {
+                    start += step.duration_ms;
+                    if t < start {
+                        return Some(step.level);
+                    }
+                }
+
+                pattern.last().map(|step| step.level)
+            }
+        }
+    }
+}
+
+/// `part` / `whole` scaled to `u16::MAX`
+fn fraction(part: u32, whole: u32) -> u16 {
+    (part.min(whole) as u64 * u16::MAX as u64 / whole.max(1) as u64) as u16
+}
+
+/// An LED whose effect is advanced with the elapsed time
+pub trait Animate {
+    /// Advance the effect by `elapsed_ms` and update the duty cycles
+    fn tick(&mut self, elapsed_ms: u32);
+}
+
+/// Set the duty cycle of a channel from a level
+fn set_level<P: PwmPin<Duty = u16>>(pin: &mut P, curve: Curve, level: u8) {
+    let duty = curve.apply(level) as u32 * pin.get_max_duty() as u32 / u16::MAX as u32;
+    pin.set_duty(duty as u16);
+}
+
+/// A single color LED on one PWM channel
+pub struct Led<P> {
+    pin: P,
+    curve: Curve,
+    animation: Animation<u8>,
+}
+
+impl<P: PwmPin<Duty = u16>> Led<P> {
+    /// Take over a PWM channel, the LED is off
+    pub fn new(mut pin: P, curve: Curve) -> Self {
+        set_level(&mut pin, curve, 0);
+        pin.enable();
+
+        Self {
+            pin,
+            curve,
+            animation: Animation::new(Effect::Solid(0)),
+        }
+    }
+
+    /// Start playing an effect from its beginning
+    pub fn set_effect(&mut self, effect: Effect<u8>) {
+        self.animation = Animation::new(effect);
+        self.update();
+    }
+
+    /// Set a constant brightness
+    pub fn set_brightness(&mut self, level: u8) {
+        self.set_effect(Effect::Solid(level));
+    }
+
+    /// Advance the effect by `elapsed_ms` and update the duty cycle
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.animation.advance(elapsed_ms);
+        self.update();
+    }
+
+    /// Check whether a fade or a non-repeating blink pattern has finished
+    pub fn is_done(&self) -> bool {
+        self.animation.is_done()
+    }
+
+    /// Release the PWM channel
+    pub fn free(self) -> P {
+        self.pin
+    }
+
+    fn update(&mut self) {
+        if let Some(level) = self.animation.level() {
+            set_level(&mut self.pin, self.curve, level);
+        }
+    }
+}
+
+impl<P: PwmPin<Duty = u16>> Animate for Led<P> {
+    fn tick(&mut self, elapsed_ms: u32) {
+        Led::tick(self, elapsed_ms);
+    }
+}
+
+/// An RGB LED mixing its color on three PWM channels
+pub struct RgbLed<R, G, B> {
+    red: R,
+    green: G,
+    blue: B,
+    curve: Curve,
+    animation: Animation<Color>,
+}
+
+impl<R, G, B> RgbLed<R, G, B>
+where
+    R: PwmPin<Duty = u16>,
+    G: PwmPin<Duty = u16>,
+    B: PwmPin<Duty = u16>,
+{
+    /// Take over three PWM channels, the LED is off
+    pub fn new(red: R, green: G, blue: B, curve: Curve) -> Self {
+        let mut led = Self {
+            red,
+            green,
+            blue,
+            curve,
+            animation: Animation::new(Effect::Solid(Color::OFF)),
+        };
+
+        led.update();
+        led.red.enable();
+        led.green.enable();
+        led.blue.enable();
+
+        led
+    }
+
+    /// Start playing an effect from its beginning
+    pub fn set_effect(&mut self, effect: Effect<Color>) {
+        self.animation = Animation::new(effect);
+        self.update();
+    }
+
+    /// Set a constant color
+    pub fn set_color(&mut self, color: Color) {
+        self.set_effect(Effect::Solid(color));
+    }
+
+    /// Advance the effect by `elapsed_ms` and update the duty cycles
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.animation.advance(elapsed_ms);
+        self.update();
+    }
+
+    /// Check whether a fade or a non-repeating blink pattern has finished
+    pub fn is_done(&self) -> bool {
+        self.animation.is_done()
+    }
+
+    /// Release the PWM channels
+    pub fn free(self) -> (R, G, B) {
+        (self.red, self.green, self.blue)
+    }
+
+    fn update(&mut self) {
+        if let Some(color) = self.animation.level() {
+            set_level(&mut self.red, self.curve, color.r);
+            set_level(&mut self.green, self.curve, color.g);
+            set_level(&mut self.blue, self.curve, color.b);
+        }
+    }
+}
+
+impl<R, G, B> Animate for RgbLed<R, G, B>
+where
+    R: PwmPin<Duty = u16>,
+    G: PwmPin<Duty = u16>,
+    B: PwmPin<Duty = u16>,
+{
+    fn tick(&mut self, elapsed_ms: u32) {
+        RgbLed::tick(self, elapsed_ms);
+    }
+}
+
+/// An LED shared between the application and `SWTIM_Handler`
+///
+/// The LED is placed in a `static` and accessed within a critical section,
+/// e.g. to change its effect. `None` is skipped by the [`LedTimer`].
+pub type SharedLed<L> = Mutex<RefCell<Option<L>>>;
+
+/// Shared LED as ticked from the interrupt, independent of its type
+trait Tick: Sync {
+    fn tick(&self, cs: &CriticalSection, elapsed_ms: u32);
+}
+
+impl<L: Animate + Send> Tick for SharedLed<L> {
+    fn tick(&self, cs: &CriticalSection, elapsed_ms: u32) {
+        if let Some(led) = self.borrow(cs).borrow_mut().as_mut() {
+            led.tick(elapsed_ms);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// [`MAX_LEDS`] LEDs are attached already
+    Full,
+}
+
+struct State {
+    /// Period of the count down timer
+    period_ms: u32,
+    leds: [Option<&'static dyn Tick>; MAX_LEDS],
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            period_ms: 0,
+            leds: [None; MAX_LEDS],
+        }
+    }
+}
+
+/// Effects of up to [`MAX_LEDS`] LEDs played by the SWTIM interrupt
+///
+/// The [`CountDownTimer`] runs periodically, every period the attached LEDs
+/// are ticked from `SWTIM_Handler`. Shorter periods give smoother fades at
+/// the cost of more interrupts, 10 to 20 ms are sufficient for the eye.
+pub struct LedTimer {
+    timer: CountDownTimer,
+}
+
+impl LedTimer {
+    /// Take over the count down timer and start it with `period`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn new(mut timer: CountDownTimer, period: MilliSeconds) -> Self {
+        let period_ms = period.ticks();
+        assert!(period_ms > 0);
+
+        interrupt::free(|cs| {
+            *STATE.borrow(cs).borrow_mut() = State {
+                period_ms,
+                ..State::new()
+            };
+        });
+
+        timer.start(MicroSeconds::from_ticks(period_ms.saturating_mul(1000)));
+
+        Self { timer }
+    }
+
+    /// Tick `led` from the interrupt, until the timer is released
+    pub fn attach<L: Animate + Send>(&mut self, led: &'static SharedLed<L>) -> Result<(), Error> {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            let slot = state
+                .leds
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(Error::Full)?;
+            *slot = Some(led);
+
+            Ok(())
+        })
+    }
+
+    /// Stop the timer and detach all LEDs
+    pub fn free(self) -> CountDownTimer {
+        let mut timer = self.timer;
+        timer.cancel().ok();
+
+        interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = State::new());
+
+        timer
+    }
+}
+
+/// Tick the attached LEDs, called from `SWTIM_Handler` when the period of the
+/// count down timer has elapsed
+pub(super) fn on_interrupt() {
+    interrupt::free(|cs| {
+        let state = STATE.borrow(cs).borrow();
+
+        for led in state.leds.iter().flatten() {
+            led.tick(cs, state.period_ms);
+        }
+    });
+}
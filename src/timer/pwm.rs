@@ -0,0 +1,383 @@
+//! PWM on the Timer0 outputs PWM0 and PWM1.
+//!
+//! Timer0 drives PWM0 high for `M + 1` and low for `N + 1` timer clock ticks,
+//! PWM1 outputs the inverted PWM0 signal. The timer clock dividers are chosen
+//! for the highest duty cycle resolution which still reaches the requested
+//! frequency.
+//!
+//! The timer cannot generate a constant level. At 0% and 100% duty cycle the
+//! pins are driven as GPIO outputs instead, for this they have to be handed
+//! over with [`Timer0Pwm::set_pwm0_pin`] and [`Timer0Pwm::set_pwm1_pin`].
+
+use core::{cell::RefCell, convert::Infallible};
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    crg_top::Clocks,
+    gpio::{self, AfPwm0, AfPwm1, Pin},
+    hal::{self, digital::v2::PinState},
+    pac::{CRG_TOP, TIMER0},
+    time::Hertz,
+};
+
+use super::{BaseClockDiv, ClockSel, PwmMode, Timer0};
+
+/// Dividers of the system clock, the divide by 10 of Timer0 only applies to
+/// the ON-counter and does not affect the PWM outputs
+const PRESCALERS: [(BaseClockDiv, u32); 4] = [
+    (BaseClockDiv::Div1, 1),
+    (BaseClockDiv::Div2, 2),
+    (BaseClockDiv::Div4, 4),
+    (BaseClockDiv::Div8, 8),
+];
+
+/// Shortest period in timer clock ticks, both phases last at least one tick
+const MIN_PERIOD: u32 = 2;
+
+/// Longest period in timer clock ticks
+const MAX_PERIOD: u32 = u16::MAX as u32;
+
+/// Alternate functions of PWM0 and PWM1
+const PIDS: [u8; 2] = [16, 17];
+
+/// Pins of PWM0 and PWM1, driven directly for a constant level
+static PINS: Mutex<RefCell<[Option<u8>; 2]>> = Mutex::new(RefCell::new([None; 2]));
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Channel {
+    Pwm0,
+    Pwm1,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The frequency exceeds half of the system clock
+    FrequencyTooHigh,
+    /// The frequency is below what the low power clock is able to reach
+    FrequencyTooLow,
+}
+
+/// Timer clock configuration for one PWM frequency
+#[derive(Clone, Copy)]
+pub(super) struct Timing {
+    clk_sel: ClockSel,
+    base_div: BaseClockDiv,
+    /// Resulting timer clock in Hz
+    pub(super) clock: u32,
+    /// Period in timer clock ticks
+    pub(super) period: u32,
+}
+
+impl Timing {
+    pub(super) fn new(clocks: &Clocks, freq: Hertz) -> Result<Self, Error> {
+        let freq = freq.raw();
+        if freq == 0 {
+            return Err(Error::FrequencyTooLow);
+        }
+
+        let sys_clk = clocks.sys_clk().raw();
+        if sys_clk / freq < MIN_PERIOD {
+            return Err(Error::FrequencyTooHigh);
+        }
+
+        let sys_clk_timing = PRESCALERS
+            .iter()
+            .map(|&(base_div, div)| Self {
+                clk_sel: ClockSel::SystemClock,
+                base_div,
+                clock: sys_clk / div,
+                period: sys_clk / div / freq,
+            })
+            .find(|timing| timing.period <= MAX_PERIOD);
+
+        // Very low frequencies are only reachable with the low power clock
+        let timing = sys_clk_timing.unwrap_or_else(|| {
+            let lp_clk = clocks.lp_clk().raw();
+
+            Self {
+                clk_sel: ClockSel::LowPowerClock,
+                base_div: BaseClockDiv::Div1,
+                clock: lp_clk,
+                period: lp_clk / freq,
+            }
+        });
+
+        if timing.period > MAX_PERIOD {
+            return Err(Error::FrequencyTooLow);
+        }
+
+        if timing.period < MIN_PERIOD {
+            return Err(Error::FrequencyTooHigh);
+        }
+
+        Ok(timing)
+    }
+
+    /// Program the timer clock, also used from interrupt context
+    pub(super) fn apply(&self) {
+        let crg_top = unsafe { &*CRG_TOP::ptr() };
+        let timer = unsafe { &*TIMER0::ptr() };
+
+        crg_top
+            .clk_per_reg
+            .modify(|_, w| unsafe { w.tmr_div().bits(self.base_div as u8) });
+        timer.timer0_ctrl_reg.modify(|_, w| {
+            w.tim0_clk_sel().bit(self.clk_sel as u8 == 1);
+            // Outputs are high during the M phase
+            w.pwm_mode().bit(PwmMode::High as u8 == 1);
+            w
+        });
+    }
+}
+
+/// Program the high time of PWM0, limited to the period, also used from
+/// interrupt context
+///
+/// A high time of zero or of the full period drives the pins at a constant
+/// level.
+pub(super) fn write_duty(period: u16, duty: u16) -> u16 {
+    let timer = unsafe { &*TIMER0::ptr() };
+    let duty = duty.min(period);
+
+    let level = match duty {
+        0 => Some(PinState::Low),
+        duty if duty == period => Some(PinState::High),
+        duty => {
+            timer
+                .timer0_reload_m_reg
+                .write(|w| unsafe { w.tim0_m().bits(duty - 1) });
+            timer
+                .timer0_reload_n_reg
+                .write(|w| unsafe { w.tim0_n().bits(period - duty - 1) });
+
+            None
+        }
+    };
+
+    // PWM1 is the inverse of PWM0
+    let levels = [level, level.map(|level| !level)];
+
+    interrupt::free(|cs| {
+        let pins = PINS.borrow(cs).borrow();
+
+        for ((pin, pid), level) in pins.iter().zip(PIDS).zip(levels) {
+            if let Some(pin) = pin {
+                gpio::override_alternate(*pin, pid, level);
+            }
+        }
+    });
+
+    duty
+}
+
+fn set_pin(channel: Channel, pin: Option<u8>) {
+    interrupt::free(|cs| PINS.borrow(cs).borrow_mut()[channel as usize] = pin);
+}
+
+/// Timer0 used as PWM generator
+///
+/// As PWM1 is the inverse of PWM0, both channels share the frequency and are
+/// enabled and disabled together. The duty cycle is given in timer clock ticks
+/// and ranges from 0 to [`Timer0Pwm::max_duty`].
+pub struct Timer0Pwm {
+    timer: Timer0,
+    clocks: Clocks,
+    timing: Timing,
+    /// High time of PWM0 in timer clock ticks
+    duty: u16,
+    pwm0: Option<Pin<AfPwm0>>,
+    pwm1: Option<Pin<AfPwm1>>,
+}
+
+impl Timer0 {
+    /// Use Timer0 as PWM generator running at `freq` with 50% duty cycle
+    ///
+    /// The timer is stopped until the PWM is enabled.
+    pub fn into_pwm(mut self, clocks: &Clocks, freq: Hertz) -> Result<Timer0Pwm, Error> {
+        let timing = Timing::new(clocks, freq)?;
+
+        self.stop();
+        self.enable_clock();
+
+        let mut pwm = Timer0Pwm {
+            timer: self,
+            clocks: *clocks,
+            timing,
+            duty: 0,
+            pwm0: None,
+            pwm1: None,
+        };
+
+        pwm.duty = pwm.max_duty() / 2;
+        pwm.apply();
+
+        Ok(pwm)
+    }
+}
+
+impl Timer0Pwm {
+    pub fn set_pwm0_pin(mut self, pin: Pin<AfPwm0>) -> Self {
+        set_pin(Channel::Pwm0, Some(pin.pin()));
+        self.pwm0 = Some(pin);
+        self.apply();
+        self
+    }
+
+    pub fn set_pwm1_pin(mut self, pin: Pin<AfPwm1>) -> Self {
+        set_pin(Channel::Pwm1, Some(pin.pin()));
+        self.pwm1 = Some(pin);
+        self.apply();
+        self
+    }
+
+    pub fn enable(&mut self) {
+        self.timer.start();
+    }
+
+    pub fn disable(&mut self) {
+        self.timer.stop();
+    }
+
+    /// Change the PWM frequency, keeping the duty cycle ratio
+    pub fn set_frequency(&mut self, freq: Hertz) -> Result<(), Error> {
+        let timing = Timing::new(&self.clocks, freq)?;
+        let old_period = self.timing.period;
+
+        self.timing = timing;
+        self.duty = (self.duty as u32 * timing.period / old_period) as u16;
+        self.apply();
+
+        Ok(())
+    }
+
+    /// Frequency which is actually generated
+    pub fn frequency(&self) -> Hertz {
+        Hertz::from_raw(self.timing.clock / self.timing.period)
+    }
+
+    /// Period in timer clock ticks, the duty cycle of a full period
+    pub fn max_duty(&self) -> u16 {
+        self.timing.period as u16
+    }
+
+    /// High time of PWM0 in timer clock ticks
+    pub fn duty(&self) -> u16 {
+        self.duty
+    }
+
+    /// Set the high time of PWM0 in timer clock ticks
+    ///
+    /// Zero and [`max_duty`](Self::max_duty) drive the pins at a constant
+    /// level, larger values are limited to the period.
+    pub fn set_duty_ticks(&mut self, duty: u16) {
+        self.duty = write_duty(self.max_duty(), duty);
+    }
+
+    /// Release Timer0 and the pins
+    pub fn free(mut self) -> (Timer0, Option<Pin<AfPwm0>>, Option<Pin<AfPwm1>>) {
+        self.timer.stop();
+
+        // Hand the pins back to the PWM function
+        let period = self.max_duty();
+        write_duty(period, period / 2);
+        set_pin(Channel::Pwm0, None);
+        set_pin(Channel::Pwm1, None);
+
+        (self.timer, self.pwm0, self.pwm1)
+    }
+
+    /// Program the frequency and duty cycle, e.g. after the tone generator
+    /// has changed them
+    pub(super) fn apply(&mut self) {
+        self.timing.apply();
+        self.set_duty_ticks(self.duty);
+    }
+}
+
+impl hal::Pwm for Timer0Pwm {
+    type Channel = Channel;
+    /// The period is given as its frequency
+    type Time = Hertz;
+    type Duty = u16;
+
+    fn disable(&mut self, _channel: Self::Channel) {
+        Timer0Pwm::disable(self);
+    }
+
+    fn enable(&mut self, _channel: Self::Channel) {
+        Timer0Pwm::enable(self);
+    }
+
+    /// Frequency of the PWM, the inverse of the period
+    fn get_period(&self) -> Self::Time {
+        self.frequency()
+    }
+
+    fn get_duty(&self, channel: Self::Channel) -> Self::Duty {
+        match channel {
+            Channel::Pwm0 => self.duty,
+            Channel::Pwm1 => self.max_duty() - self.duty,
+        }
+    }
+
+    fn get_max_duty(&self) -> Self::Duty {
+        self.max_duty()
+    }
+
+    fn set_duty(&mut self, channel: Self::Channel, duty: Self::Duty) {
+        match channel {
+            Channel::Pwm0 => self.set_duty_ticks(duty),
+            Channel::Pwm1 => self.set_duty_ticks(self.max_duty().saturating_sub(duty)),
+        }
+    }
+
+    /// Set the PWM frequency
+    ///
+    /// Panics if the frequency is out of range, see [`Timer0Pwm::set_frequency`].
+    fn set_period<P>(&mut self, period: P)
+    where
+        P: Into<Self::Time>,
+    {
+        self.set_frequency(period.into())
+            .expect("PWM frequency out of range");
+    }
+}
+
+impl hal::PwmPin for Timer0Pwm {
+    type Duty = u16;
+
+    fn disable(&mut self) {
+        Timer0Pwm::disable(self);
+    }
+
+    fn enable(&mut self) {
+        Timer0Pwm::enable(self);
+    }
+
+    fn get_duty(&self) -> Self::Duty {
+        self.duty
+    }
+
+    fn get_max_duty(&self) -> Self::Duty {
+        self.max_duty()
+    }
+
+    fn set_duty(&mut self, duty: Self::Duty) {
+        self.set_duty_ticks(duty);
+    }
+}
+
+impl embedded_hal_1::pwm::ErrorType for Timer0Pwm {
+    type Error = Infallible;
+}
+
+impl embedded_hal_1::pwm::SetDutyCycle for Timer0Pwm {
+    fn max_duty_cycle(&self) -> u16 {
+        self.max_duty()
+    }
+
+    fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+        self.set_duty_ticks(duty);
+        Ok(())
+    }
+}
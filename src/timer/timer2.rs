@@ -0,0 +1,470 @@
+//! Triple PWM (Timer2) driving the outputs PWM2 to PWM7.
+//!
+//! All six outputs share one period of `TRIPLE_PWM_FREQUENCY + 1` clock ticks.
+//! Within that period each output is high from its start cycle up to its end
+//! cycle. The fast clock of Timer2 is the system clock divided by the
+//! `TMR_DIV` divider it shares with Timer0, so changing the Timer0 clock
+//! divider also changes the Timer2 frequency.
+//!
+//! Timer2 is part of the `TIMER0` register block, its registers are disjoint
+//! from the ones used by [`Timer0`](super::Timer0).
+
+use core::marker::PhantomData;
+
+use crate::{
+    crg_top::Clocks,
+    gpio::{AfPwm2, AfPwm3, AfPwm4, AfPwm5, AfPwm6, AfPwm7, Pin},
+    hal,
+    pac::{timer0::RegisterBlock, CRG_TOP, TIMER0},
+    time::Hertz,
+};
+
+use super::ClockSel;
+
+/// Shortest period in clock ticks
+const MIN_PERIOD: u32 = 2;
+
+/// Longest period in clock ticks, limited by the 14 bit end cycle of a
+/// channel which is high during the whole period
+const MAX_PERIOD: u32 = (1 << 14) - 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// The frequency exceeds half of the Timer2 clock
+    FrequencyTooHigh,
+    /// The frequency is below what the low power clock is able to reach
+    FrequencyTooLow,
+}
+
+/// State of the Timer2 outputs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    Stopped,
+    /// Paused by software, the outputs are held low
+    Paused,
+    Running,
+}
+
+/// Behaviour of Timer2 while the system sleeps
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SleepMode {
+    /// Timer2 is not prepared for sleep (reset value)
+    ///
+    /// It may run from the system clock, which stops while the system sleeps,
+    /// and the outputs stop with it. Powering the timer power domain down is
+    /// left to the application, as the domain is shared with Timer0 and
+    /// Timer1.
+    PowerDown,
+    /// The timer power domain stays powered and the PWM keeps running from the
+    /// low power clock
+    KeepRunning,
+}
+
+fn regs() -> &'static RegisterBlock {
+    unsafe { &*TIMER0::ptr() }
+}
+
+/// Number of clock ticks per PWM period
+fn period() -> u16 {
+    regs().triple_pwm_frequency.read().pwm_freq().bits() + 1
+}
+
+/// Select the clock and set the period in clock ticks
+pub(super) fn set_period(clk_sel: ClockSel, ticks: u16) {
+    let regs = regs();
+
+    regs.triple_pwm_ctrl_reg
+        .modify(|_, w| w.triple_pwm_clk_sel().bit(clk_sel as u8 == 1));
+    regs.triple_pwm_frequency
+        .write(|w| unsafe { w.pwm_freq().bits(ticks - 1) });
+}
+
+pub(super) fn set_enabled(enable: bool) {
+    regs()
+        .triple_pwm_ctrl_reg
+        .modify(|_, w| w.triple_pwm_enable().bit(enable));
+}
+
+/// Timer2, controlling the frequency shared by all PWM channels
+pub struct Timer2 {
+    sleep_mode: SleepMode,
+}
+
+/// The PWM channels of Timer2, not yet bound to a pin
+pub struct Channels {
+    pub pwm2: Channel<AfPwm2>,
+    pub pwm3: Channel<AfPwm3>,
+    pub pwm4: Channel<AfPwm4>,
+    pub pwm5: Channel<AfPwm5>,
+    pub pwm6: Channel<AfPwm6>,
+    pub pwm7: Channel<AfPwm7>,
+}
+
+impl Timer2 {
+    pub(super) fn new() -> (Self, Channels) {
+        let channels = Channels {
+            pwm2: Channel { _af: PhantomData },
+            pwm3: Channel { _af: PhantomData },
+            pwm4: Channel { _af: PhantomData },
+            pwm5: Channel { _af: PhantomData },
+            pwm6: Channel { _af: PhantomData },
+            pwm7: Channel { _af: PhantomData },
+        };
+
+        (
+            Self {
+                sleep_mode: SleepMode::PowerDown,
+            },
+            channels,
+        )
+    }
+
+    /// Enable the timer clock and set the PWM frequency
+    pub fn init(&mut self, clocks: &Clocks, freq: Hertz) -> Result<(), Error> {
+        let crg_top = unsafe { &*CRG_TOP::ptr() };
+        crg_top.clk_per_reg.modify(|_, w| w.tmr_enable().set_bit());
+
+        self.set_frequency(clocks, freq)
+    }
+
+    /// Set the PWM frequency
+    ///
+    /// The fast clock is used whenever it reaches the frequency, which gives the
+    /// best duty cycle resolution, unless Timer2 keeps running in sleep. The
+    /// start and end cycles of all channels are rescaled, so their duty cycles
+    /// and phases are kept.
+    pub fn set_frequency(&mut self, clocks: &Clocks, freq: Hertz) -> Result<(), Error> {
+        let freq = freq.raw();
+        if freq == 0 {
+            return Err(Error::FrequencyTooLow);
+        }
+
+        let crg_top = unsafe { &*CRG_TOP::ptr() };
+        let tmr_div = crg_top.clk_per_reg.read().tmr_div().bits();
+        let fast_clk = clocks.sys_clk().raw() >> tmr_div;
+
+        let (clk_sel, ticks) = match fast_clk / freq {
+            ticks if ticks <= MAX_PERIOD && self.sleep_mode == SleepMode::PowerDown => {
+                (ClockSel::SystemClock, ticks)
+            }
+            _ => (ClockSel::LowPowerClock, clocks.lp_clk().raw() / freq),
+        };
+
+        if ticks < MIN_PERIOD {
+            return Err(Error::FrequencyTooHigh);
+        }
+
+        if ticks > MAX_PERIOD {
+            return Err(Error::FrequencyTooLow);
+        }
+
+        let old_period = period() as u32;
+        set_period(clk_sel, ticks as u16);
+
+        for index in 2..=7 {
+            let (start, end) = cycles(index);
+            let rescale = |cycle: u16| (cycle as u32 * ticks / old_period) as u16;
+
+            set_cycles(index, rescale(start), rescale(end));
+        }
+
+        Ok(())
+    }
+
+    /// Frequency which is actually generated
+    pub fn frequency(&self, clocks: &Clocks) -> Hertz {
+        let regs = regs();
+
+        let clock = if regs.triple_pwm_ctrl_reg.read().triple_pwm_clk_sel().bit() {
+            let crg_top = unsafe { &*CRG_TOP::ptr() };
+            clocks.sys_clk().raw() >> crg_top.clk_per_reg.read().tmr_div().bits()
+        } else {
+            clocks.lp_clk().raw()
+        };
+
+        Hertz::from_raw(clock / period() as u32)
+    }
+
+    pub fn start(&mut self) {
+        set_enabled(true);
+    }
+
+    pub fn stop(&mut self) {
+        set_enabled(false);
+    }
+
+    /// Hold all outputs low until [`Timer2::resume`] is called
+    pub fn pause(&mut self) {
+        regs()
+            .triple_pwm_ctrl_reg
+            .modify(|_, w| w.sw_pause_en().set_bit());
+    }
+
+    pub fn resume(&mut self) {
+        regs()
+            .triple_pwm_ctrl_reg
+            .modify(|_, w| w.sw_pause_en().clear_bit());
+    }
+
+    /// Let the hardware pause the outputs while the radio is active
+    ///
+    /// Avoids PWM edges causing spurs during RF activity.
+    pub fn set_hw_pause(&mut self, enable: bool) {
+        regs()
+            .triple_pwm_ctrl_reg
+            .modify(|_, w| w.hw_pause_en().bit(enable));
+    }
+
+    /// Select whether Timer2 keeps running while the system sleeps
+    ///
+    /// Keeping it running requires the low power clock, Timer2 is switched over
+    /// at the current frequency if necessary, and keeps the timer power domain
+    /// powered in sleep. The power domain is never powered down here, as it is
+    /// shared with the other timers.
+    pub fn set_sleep_mode(&mut self, clocks: &Clocks, mode: SleepMode) -> Result<(), Error> {
+        let keep_running = mode == SleepMode::KeepRunning;
+
+        if keep_running && regs().triple_pwm_ctrl_reg.read().triple_pwm_clk_sel().bit() {
+            let freq = self.frequency(clocks);
+
+            self.sleep_mode = mode;
+            if let Err(error) = self.set_frequency(clocks, freq) {
+                self.sleep_mode = SleepMode::PowerDown;
+                return Err(error);
+            }
+        }
+
+        if keep_running {
+            let crg_top = unsafe { &*CRG_TOP::ptr() };
+            crg_top
+                .pmu_ctrl_reg
+                .modify(|_, w| w.tim_sleep().clear_bit());
+        }
+
+        self.sleep_mode = mode;
+        Ok(())
+    }
+
+    pub fn sleep_mode(&self) -> SleepMode {
+        self.sleep_mode
+    }
+
+    pub fn state(&self) -> State {
+        let ctrl = regs().triple_pwm_ctrl_reg.read();
+
+        if !ctrl.triple_pwm_enable().bit() {
+            State::Stopped
+        } else if ctrl.sw_pause_en().bit() {
+            State::Paused
+        } else {
+            State::Running
+        }
+    }
+
+    /// Check whether the outputs are currently generated
+    pub fn is_running(&self) -> bool {
+        self.state() == State::Running
+    }
+}
+
+/// An unbound Timer2 PWM channel
+pub struct Channel<AF> {
+    _af: PhantomData<AF>,
+}
+
+/// A Timer2 PWM channel driving a pin
+///
+/// The duty cycle is given in clock ticks, as a fraction of
+/// [`PwmChannel::max_duty`], the period of Timer2. The high phase starts at
+/// the phase offset and is cut off at the end of the period.
+pub struct PwmChannel<AF> {
+    pin: Pin<AF>,
+    /// Start and end cycle and the period they refer to while disabled
+    disabled: Option<(u16, u16, u16)>,
+}
+
+impl<AF: Output> Channel<AF> {
+    /// Bind the channel to a pin, the output stays low until a duty cycle is set
+    pub fn into_pwm(self, pin: Pin<AF>) -> PwmChannel<AF> {
+        set_cycles(AF::INDEX, 0, 0);
+
+        PwmChannel {
+            pin,
+            disabled: None,
+        }
+    }
+}
+
+impl<AF: Output> PwmChannel<AF> {
+    /// Period in clock ticks, the duty cycle of a channel which is always high
+    pub fn max_duty(&self) -> u16 {
+        period()
+    }
+
+    /// High time in clock ticks
+    pub fn duty(&self) -> u16 {
+        let (start, end) = self.cycles();
+        end.saturating_sub(start)
+    }
+
+    /// Set the high time in clock ticks, keeping the phase offset
+    pub fn set_duty(&mut self, duty: u16) {
+        let (start, _) = self.cycles();
+        self.set_cycles(start, start.saturating_add(duty));
+    }
+
+    /// Set the high time in percent of the period
+    pub fn set_duty_percent(&mut self, percent: u8) {
+        self.set_duty(self.percent_to_ticks(percent));
+    }
+
+    /// Offset of the high phase from the start of the period in clock ticks
+    pub fn phase(&self) -> u16 {
+        self.cycles().0
+    }
+
+    /// Delay the high phase by `phase` clock ticks, keeping the duty cycle
+    ///
+    /// Staggering the channels spreads the switching edges over the period.
+    pub fn set_phase(&mut self, phase: u16) {
+        let duty = self.duty();
+        let start = phase.min(self.max_duty());
+
+        self.set_cycles(start, start.saturating_add(duty));
+    }
+
+    /// Delay the high phase by `percent` of the period
+    pub fn set_phase_percent(&mut self, percent: u8) {
+        self.set_phase(self.percent_to_ticks(percent));
+    }
+
+    pub fn enable(&mut self) {
+        if let Some((start, end, period)) = self.disabled.take() {
+            let max_duty = self.max_duty() as u32;
+            let rescale = |cycle: u16| (cycle as u32 * max_duty / period as u32) as u16;
+
+            set_cycles(AF::INDEX, rescale(start), rescale(end));
+        }
+    }
+
+    /// Drive the output low
+    pub fn disable(&mut self) {
+        if self.disabled.is_none() {
+            let (start, end) = cycles(AF::INDEX);
+            self.disabled = Some((start, end, self.max_duty()));
+
+            set_cycles(AF::INDEX, 0, 0);
+        }
+    }
+
+    /// Release the channel and the pin
+    pub fn free(mut self) -> (Channel<AF>, Pin<AF>) {
+        self.disable();
+        (Channel { _af: PhantomData }, self.pin)
+    }
+
+    fn percent_to_ticks(&self, percent: u8) -> u16 {
+        (self.max_duty() as u32 * percent.min(100) as u32 / 100) as u16
+    }
+
+    /// Start and end cycle, also while the channel is disabled
+    fn cycles(&self) -> (u16, u16) {
+        match self.disabled {
+            Some((start, end, period)) => {
+                let max_duty = self.max_duty() as u32;
+                let rescale = |cycle: u16| (cycle as u32 * max_duty / period as u32) as u16;
+
+                (rescale(start), rescale(end))
+            }
+            None => cycles(AF::INDEX),
+        }
+    }
+
+    fn set_cycles(&mut self, start: u16, end: u16) {
+        let end = end.min(self.max_duty());
+
+        match self.disabled {
+            Some(_) => self.disabled = Some((start, end, self.max_duty())),
+            None => set_cycles(AF::INDEX, start, end),
+        }
+    }
+}
+
+impl<AF: Output> hal::PwmPin for PwmChannel<AF> {
+    type Duty = u16;
+
+    fn disable(&mut self) {
+        PwmChannel::disable(self);
+    }
+
+    fn enable(&mut self) {
+        PwmChannel::enable(self);
+    }
+
+    fn get_duty(&self) -> Self::Duty {
+        self.duty()
+    }
+
+    fn get_max_duty(&self) -> Self::Duty {
+        self.max_duty()
+    }
+
+    fn set_duty(&mut self, duty: Self::Duty) {
+        PwmChannel::set_duty(self, duty);
+    }
+}
+
+/// Alternate functions of the Timer2 outputs
+pub trait Output: crate::Sealed {
+    /// Number of the PWM output
+    const INDEX: u8;
+}
+
+macro_rules! timer2_outputs {
+    ($($AF:ty => ($index:literal, $start:ident, $end:ident),)+) => {
+        $(
+            impl crate::Sealed for $AF {}
+
+            impl Output for $AF {
+                const INDEX: u8 = $index;
+            }
+        )+
+
+        fn cycles(index: u8) -> (u16, u16) {
+            let regs = regs();
+
+            match index {
+                $(
+                    $index => (
+                        regs.$start.read().start_cycle().bits(),
+                        regs.$end.read().end_cycle().bits(),
+                    ),
+                )+
+                _ => unreachable!(),
+            }
+        }
+
+        pub(super) fn set_cycles(index: u8, start: u16, end: u16) {
+            let regs = regs();
+
+            match index {
+                $(
+                    $index => {
+                        regs.$start.write(|w| unsafe { w.start_cycle().bits(start) });
+                        regs.$end.write(|w| unsafe { w.end_cycle().bits(end) });
+                    }
+                )+
+                _ => unreachable!(),
+            }
+        }
+    };
+}
+
+timer2_outputs!(
+    AfPwm2 => (2, pwm2_start_cycle, pwm2_end_cycle),
+    AfPwm3 => (3, pwm3_start_cycle, pwm3_end_cycle),
+    AfPwm4 => (4, pwm4_start_cycle, pwm4_end_cycle),
+    AfPwm5 => (5, pwm5_start_cycle, pwm5_end_cycle),
+    AfPwm6 => (6, pwm6_start_cycle, pwm6_end_cycle),
+    AfPwm7 => (7, pwm7_start_cycle, pwm7_end_cycle),
+);
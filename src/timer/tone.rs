@@ -0,0 +1,355 @@
+//! Tone generator for piezo buzzers on the Timer0 PWM.
+//!
+//! The PWM generates the tone while the ON-counter of Timer0 times the notes.
+//! The SWTIM interrupt moves on to the next note, so the CPU is free to sleep
+//! while a melody plays in the background.
+
+use core::cell::RefCell;
+
+use void::Void;
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    crg_top::Clocks,
+    nvic::{Irq, Nvic},
+    pac::{CRG_TOP, TIMER0},
+    time::{Hertz, MilliSeconds},
+};
+
+use super::pwm::{self, Timer0Pwm, Timing};
+
+/// Longest ON-counter period in timer clock ticks
+const MAX_TICKS: u64 = u16::MAX as u64 + 1;
+
+/// A tone of a melody
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Note {
+    /// Frequency of the tone, zero for a rest
+    pub freq: Hertz,
+    pub duration: MilliSeconds,
+}
+
+impl Note {
+    pub const fn new(freq_hz: u32, duration_ms: u32) -> Self {
+        Self {
+            freq: Hertz::from_raw(freq_hz),
+            duration: MilliSeconds::from_ticks(duration_ms),
+        }
+    }
+
+    /// Silence for `duration_ms`
+    pub const fn rest(duration_ms: u32) -> Self {
+        Self::new(0, duration_ms)
+    }
+
+    pub fn is_rest(&self) -> bool {
+        self.freq.raw() == 0
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    Idle,
+    Note,
+    Melody {
+        notes: &'static [Note],
+        index: usize,
+        repeat: bool,
+    },
+}
+
+struct State {
+    source: Source,
+    clocks: Option<Clocks>,
+    /// Volume in percent
+    volume: u8,
+    /// The current note is a rest
+    rest: bool,
+    /// ON-counter periods left until the current note ends
+    remaining: u32,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            source: Source::Idle,
+            clocks: None,
+            volume: 100,
+            rest: false,
+            remaining: 0,
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        !matches!(self.source, Source::Idle)
+    }
+
+    fn start_note(&mut self, note: &Note) {
+        self.rest = note.is_rest();
+
+        if let Some(clocks) = self.clocks {
+            self.remaining = start_note(&clocks, note, self.volume);
+        }
+    }
+
+    fn next_note(&mut self) {
+        match self.source {
+            Source::Idle => {}
+            Source::Note => self.finish(),
+            Source::Melody {
+                notes,
+                index,
+                repeat,
+            } => {
+                let index = match index + 1 {
+                    next if next < notes.len() => next,
+                    _ if repeat => 0,
+                    _ => return self.finish(),
+                };
+
+                self.source = Source::Melody {
+                    notes,
+                    index,
+                    repeat,
+                };
+                self.start_note(&notes[index]);
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        stop_timer();
+        self.source = Source::Idle;
+    }
+}
+
+fn regs() -> &'static crate::pac::timer0::RegisterBlock {
+    unsafe { &*TIMER0::ptr() }
+}
+
+fn stop_timer() {
+    regs()
+        .timer0_ctrl_reg
+        .modify(|_, w| w.tim0_ctrl().clear_bit());
+}
+
+/// PWM period as currently programmed
+fn period() -> u16 {
+    let regs = regs();
+
+    regs.timer0_reload_m_reg.read().tim0_m().bits()
+        + regs.timer0_reload_n_reg.read().tim0_n().bits()
+        + 2
+}
+
+/// Clock of the timer as currently programmed
+fn timer_clock(clocks: &Clocks) -> u32 {
+    if regs().timer0_ctrl_reg.read().tim0_clk_sel().bit() {
+        let crg_top = unsafe { &*CRG_TOP::ptr() };
+        clocks.sys_clk().raw() >> crg_top.clk_per_reg.read().tmr_div().bits()
+    } else {
+        clocks.lp_clk().raw()
+    }
+}
+
+/// Program and start the timer for one note
+///
+/// Returns the number of ON-counter periods the note lasts.
+fn start_note(clocks: &Clocks, note: &Note, volume: u8) -> u32 {
+    let regs = regs();
+
+    // Stopping the timer also resets the ON-counter
+    stop_timer();
+
+    let timing = if note.is_rest() {
+        None
+    } else {
+        Timing::new(clocks, note.freq).ok()
+    };
+
+    let clock = match timing {
+        Some(timing) => {
+            timing.apply();
+
+            // The loudest tone is generated at 50% duty cycle
+            let duty = timing.period * volume.min(100) as u32 / 200;
+            pwm::write_duty(timing.period as u16, duty as u16);
+
+            timing.clock
+        }
+        None => {
+            // Keep the previous clock, PWM0 is held low and PWM1 high
+            pwm::write_duty(period(), 0);
+
+            timer_clock(clocks)
+        }
+    };
+
+    let ticks = (note.duration.ticks() as u64 * clock as u64 / 1_000).max(1);
+
+    // The divide by 10 only slows down the ON-counter, not the tone
+    let (div10, ticks) = match ticks {
+        ticks if ticks <= MAX_TICKS => (false, ticks),
+        ticks => (true, (ticks / 10).max(1)),
+    };
+
+    let periods = (ticks + MAX_TICKS - 1) / MAX_TICKS;
+    let on = ticks / periods - 1;
+
+    regs.timer0_ctrl_reg
+        .modify(|_, w| w.tim0_clk_div().bit(!div10));
+    regs.timer0_on_reg
+        .write(|w| unsafe { w.tim0_on().bits(on as u16) });
+    regs.timer0_ctrl_reg.modify(|_, w| w.tim0_ctrl().set_bit());
+
+    periods as u32
+}
+
+/// Tone generator driving a buzzer from PWM0/PWM1
+///
+/// Rests hold PWM0 low and PWM1 high, which requires the pins to be handed to
+/// the [`Timer0Pwm`] with `set_pwm0_pin` or `set_pwm1_pin`.
+pub struct Tone {
+    pwm: Timer0Pwm,
+    clocks: Clocks,
+}
+
+impl Tone {
+    /// Take over the Timer0 PWM and enable the SWTIM interrupt
+    pub fn new(mut pwm: Timer0Pwm, clocks: &Clocks, nvic: &mut Nvic) -> Self {
+        pwm.disable();
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            state.source = Source::Idle;
+            state.clocks = Some(*clocks);
+        });
+
+        nvic.set_priority(Irq::SwTim0, 2);
+        nvic.enable_irq(Irq::SwTim0);
+
+        Self {
+            pwm,
+            clocks: *clocks,
+        }
+    }
+
+    /// Set the volume in percent, by changing the duty cycle of the tone
+    ///
+    /// Takes effect immediately, also on a tone which is playing.
+    pub fn set_volume(&mut self, percent: u8) {
+        let percent = percent.min(100);
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            state.volume = percent;
+
+            if state.is_playing() && !state.rest {
+                let period = period();
+                let duty = period as u32 * percent as u32 / 200;
+
+                pwm::write_duty(period, duty as u16);
+            }
+        });
+    }
+
+    /// Play a tone of `freq` for `duration` in the background
+    ///
+    /// Replaces whatever is currently playing.
+    pub fn play(&mut self, freq: Hertz, duration: MilliSeconds) -> Result<(), pwm::Error> {
+        let note = Note { freq, duration };
+        self.check(&note)?;
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            state.source = Source::Note;
+            state.start_note(&note);
+        });
+
+        Ok(())
+    }
+
+    /// Play a sequence of notes in the background, over and over if `repeat`
+    /// is set
+    ///
+    /// Replaces whatever is currently playing.
+    pub fn play_melody(&mut self, melody: &'static [Note], repeat: bool) -> Result<(), pwm::Error> {
+        for note in melody {
+            self.check(note)?;
+        }
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            match melody.first() {
+                Some(note) => {
+                    state.source = Source::Melody {
+                        notes: melody,
+                        index: 0,
+                        repeat,
+                    };
+                    state.start_note(note);
+                }
+                None => state.finish(),
+            }
+        });
+
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().finish());
+    }
+
+    pub fn is_playing(&self) -> bool {
+        interrupt::free(|cs| STATE.borrow(cs).borrow().is_playing())
+    }
+
+    /// Returns `nb::Error::WouldBlock` while a tone or melody is playing
+    pub fn wait(&mut self) -> nb::Result<(), Void> {
+        if self.is_playing() {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stop playing and hand back the PWM with its previous configuration
+    pub fn free(mut self) -> Timer0Pwm {
+        self.stop();
+
+        interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = State::new());
+
+        self.pwm.apply();
+        self.pwm
+    }
+
+    fn check(&self, note: &Note) -> Result<(), pwm::Error> {
+        if note.is_rest() {
+            Ok(())
+        } else {
+            Timing::new(&self.clocks, note.freq).map(|_| ())
+        }
+    }
+}
+
+/// End the current note once its duration has elapsed, called from
+/// `SWTIM_Handler`
+pub(super) fn on_interrupt() {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !state.is_playing() {
+            return;
+        }
+
+        state.remaining = state.remaining.saturating_sub(1);
+        if state.remaining == 0 {
+            state.next_note();
+        }
+    });
+}
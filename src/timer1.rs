@@ -0,0 +1,104 @@
+//! Timer1, an 11 bit timer with two capture inputs which can run from the
+//! low power clock.
+
+pub mod capture;
+pub mod monotonic;
+#[cfg(not(feature = "embassy"))]
+pub mod service;
+
+pub use capture::{FrequencyMeter, PulseMeter};
+pub use monotonic::MonoTimer;
+#[cfg(not(feature = "embassy"))]
+pub use service::TimerService;
+
+use crate::pac::{CRG_TOP, TIMER1};
+
+/// Extension trait that constrains the `TIMER1` peripheral
+pub trait Timer1Ext {
+    /// Constrains the `TIMER1` peripheral so it plays nicely with the other abstractions
+    fn constrain(self) -> Timer1;
+}
+
+impl Timer1Ext for TIMER1 {
+    fn constrain(self) -> Timer1 {
+        Timer1 { timer: self }
+    }
+}
+
+/// Largest value of the 11 bit counter
+pub const MAX_VALUE: u16 = 0x7ff;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClockSel {
+    SystemClock,
+    LowPowerClock,
+}
+
+pub struct Timer1 {
+    timer: TIMER1,
+}
+
+impl Timer1 {
+    /// Enable the timer block clock in CRG_TOP and the Timer1 clock
+    pub fn enable_clock(&mut self) {
+        let crg_top = unsafe { &*CRG_TOP::ptr() };
+        crg_top.clk_per_reg.modify(|_, w| w.tmr_enable().set_bit());
+
+        self.timer
+            .timer1_ctrl_reg
+            .modify(|_, w| w.timer1_clk_en().set_bit());
+    }
+
+    pub fn set_clock(&mut self, clk_sel: ClockSel) {
+        self.timer
+            .timer1_ctrl_reg
+            .modify(|_, w| w.timer1_use_sys_clk().bit(clk_sel == ClockSel::SystemClock));
+    }
+
+    pub fn start(&mut self) {
+        self.timer
+            .timer1_ctrl_reg
+            .modify(|_, w| w.timer1_enable().set_bit());
+    }
+
+    /// Stop the timer, the counter restarts from zero once started again
+    pub fn stop(&mut self) {
+        self.timer
+            .timer1_ctrl_reg
+            .modify(|_, w| w.timer1_enable().clear_bit());
+    }
+
+    /// Current value of the counter
+    pub fn counter(&self) -> u16 {
+        self.timer
+            .timer1_status_reg
+            .read()
+            .timer1_timer_value()
+            .bits()
+    }
+
+    /// Release the `TIMER1` peripheral
+    pub fn release(self) -> TIMER1 {
+        self.timer
+    }
+}
+
+/// Dispatch the Timer1 interrupt to the drivers
+fn on_interrupt() {
+    monotonic::on_interrupt();
+    capture::on_interrupt();
+}
+
+/// Timer1 interrupt handler
+///
+/// With the `rtic` feature RTIC owns the interrupt and calls
+/// `Monotonic::on_interrupt` of [`MonoTimer`] instead.
+///
+/// # Safety
+///
+/// Must only be invoked by the NVIC.
+#[cfg(not(feature = "rtic"))]
+#[no_mangle]
+pub unsafe extern "C" fn SWTIM1_Handler() {
+    on_interrupt();
+}
@@ -0,0 +1,582 @@
+//! Frequency and pulse width measurement with the Timer1 capture inputs.
+//!
+//! Timer1 runs freely and stores its counter value on the edges of an input
+//! pin. The 11 bit counter wraps every 2048 ticks, the wraps are counted in the
+//! interrupt to extend the time stamps.
+//!
+//! A [`FrequencyMeter`] captures the rising edges on IN1 and measures the
+//! period between them. A [`PulseMeter`] additionally captures the falling
+//! edges of the same pin on IN2, which gives the high and low times of each
+//! pulse. Both average a configurable number of periods and report
+//! [`Error::NoSignal`] once no edge has been seen within the timeout.
+//!
+//! The interrupt has to be served within half a counter wrap of 1024 ticks,
+//! 64 µs at a 16 MHz system clock, so the captured values can be assigned to
+//! the right wrap. A later interrupt is detected from the counter value, the
+//! edges are then discarded and [`Error::Overrun`] is reported. If the
+//! interrupt is held off for more than a whole wrap, a wrap is lost without
+//! notice. Use the low power clock for slow signals and when other
+//! interrupts may block for that long.
+
+use core::cell::RefCell;
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    crg_top::Clocks,
+    gpio::{Input, Pin},
+    nvic::{Irq, Nvic},
+    pac::{timer1::RegisterBlock, TIMER1},
+    time::{Hertz, MicroSeconds, MilliSeconds},
+};
+
+use super::{ClockSel, Timer1, MAX_VALUE};
+
+/// Ticks per counter wrap
+const WRAP_TICKS: u64 = MAX_VALUE as u64 + 1;
+
+const DEFAULT_TIMEOUT_MS: u32 = 1_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// No edge within the timeout, or not enough periods measured yet
+    NoSignal,
+    /// Edges have been lost, the interrupt was served too late to time stamp
+    /// them, or a pulse was not read before the next one
+    Overrun,
+}
+
+/// High and low time of one pulse
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pulse {
+    pub high: MicroSeconds,
+    pub low: MicroSeconds,
+}
+
+impl Pulse {
+    pub fn period(&self) -> MicroSeconds {
+        self.high + self.low
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Idle,
+    Frequency,
+    Pulse,
+}
+
+/// Averaged period and high time in ticks
+#[derive(Clone, Copy)]
+struct Average {
+    period: u64,
+    high: u64,
+}
+
+struct State {
+    mode: Mode,
+    /// Counter wraps since the meter was started
+    wraps: u64,
+    /// Counter wraps since the last edge
+    idle_wraps: u32,
+    timeout_wraps: u32,
+    last_rise: Option<u64>,
+    last_fall: Option<u64>,
+    /// Periods per average
+    averaging: u32,
+    samples: u32,
+    period_sum: u64,
+    high_sum: u64,
+    average: Option<Average>,
+    /// Last pulse in ticks, high and low time
+    pulse: Option<(u64, u64)>,
+    overrun: bool,
+    /// The interrupt was served too late, reported once by the averages
+    late: bool,
+    /// Rising edges since the meter was started
+    edges: u32,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            mode: Mode::Idle,
+            wraps: 0,
+            idle_wraps: 0,
+            timeout_wraps: 0,
+            last_rise: None,
+            last_fall: None,
+            averaging: 1,
+            samples: 0,
+            period_sum: 0,
+            high_sum: 0,
+            average: None,
+            pulse: None,
+            overrun: false,
+            late: false,
+            edges: 0,
+        }
+    }
+
+    /// Forget the current measurement, the next period starts from scratch
+    fn restart(&mut self) {
+        self.last_rise = None;
+        self.last_fall = None;
+        self.samples = 0;
+        self.period_sum = 0;
+        self.high_sum = 0;
+    }
+
+    fn on_wrap(&mut self) {
+        self.wraps += 1;
+        self.idle_wraps = self.idle_wraps.saturating_add(1);
+
+        if self.idle_wraps > self.timeout_wraps {
+            self.restart();
+            self.average = None;
+        }
+    }
+
+    fn on_rise(&mut self, stamp: u64) {
+        self.idle_wraps = 0;
+        self.edges = self.edges.wrapping_add(1);
+
+        if let Some(last_rise) = self.last_rise {
+            let period = stamp - last_rise;
+            let high = self
+                .last_fall
+                .filter(|&fall| fall > last_rise)
+                .map(|fall| fall - last_rise);
+
+            match (self.mode, high) {
+                (Mode::Pulse, None) => self.overrun = true,
+                (Mode::Pulse, Some(high)) => {
+                    if self.pulse.is_some() {
+                        self.overrun = true;
+                    }
+                    self.pulse = Some((high, period - high));
+                    self.add_sample(period, high);
+                }
+                _ => self.add_sample(period, 0),
+            }
+        }
+
+        self.last_rise = Some(stamp);
+    }
+
+    fn on_fall(&mut self, stamp: u64) {
+        self.idle_wraps = 0;
+        self.last_fall = Some(stamp);
+    }
+
+    fn add_sample(&mut self, period: u64, high: u64) {
+        self.period_sum += period;
+        self.high_sum += high;
+        self.samples += 1;
+
+        if self.samples >= self.averaging {
+            self.average = Some(Average {
+                period: self.period_sum / self.samples as u64,
+                high: self.high_sum / self.samples as u64,
+            });
+
+            self.samples = 0;
+            self.period_sum = 0;
+            self.high_sum = 0;
+        }
+    }
+}
+
+fn regs() -> &'static RegisterBlock {
+    unsafe { &*TIMER1::ptr() }
+}
+
+/// Measurement shared by both meters
+struct Meter<MODE> {
+    timer: Timer1,
+    pin: Pin<Input<MODE>>,
+    /// Timer clock in Hz
+    clock: u32,
+}
+
+impl<MODE> Meter<MODE> {
+    fn new(
+        mut timer: Timer1,
+        pin: Pin<Input<MODE>>,
+        clocks: &Clocks,
+        clk_sel: ClockSel,
+        mode: Mode,
+        nvic: &mut Nvic,
+    ) -> Self {
+        let clock = match clk_sel {
+            ClockSel::SystemClock => clocks.sys_clk().raw(),
+            ClockSel::LowPowerClock => clocks.lp_clk().raw(),
+        };
+
+        timer.stop();
+        timer.enable_clock();
+        timer.set_clock(clk_sel);
+        timer.timer.timer1_ctrl_reg.modify(|_, w| {
+            w.timer1_count_down_en().clear_bit();
+            w.timer1_free_run_mode_en().set_bit();
+            w.timer1_irq_en().set_bit()
+        });
+
+        // The edges must be configured while the inputs are not connected
+        let gpio_conf = pin.pin() + 1;
+        let regs = &timer.timer;
+
+        regs.timer1_capture_reg.write(|w| {
+            w.timer1_in1_event_fall_en().clear_bit();
+            w.timer1_in2_event_fall_en().set_bit()
+        });
+        regs.timer1_capture_reg.modify(|_, w| unsafe {
+            w.timer1_gpio1_conf().bits(gpio_conf);
+            w.timer1_in1_irq_en().set_bit();
+
+            if mode == Mode::Pulse {
+                w.timer1_gpio2_conf().bits(gpio_conf);
+                w.timer1_in2_irq_en().set_bit();
+            }
+
+            w
+        });
+        regs.timer1_clr_event_reg.write(|w| {
+            w.timer1_clr_timer_event().set_bit();
+            w.timer1_clr_in1_event().set_bit();
+            w.timer1_clr_in2_event().set_bit()
+        });
+
+        let mut meter = Self { timer, pin, clock };
+
+        interrupt::free(|cs| {
+            *STATE.borrow(cs).borrow_mut() = State {
+                mode,
+                ..State::new()
+            }
+        });
+        meter.set_timeout(MilliSeconds::from_ticks(DEFAULT_TIMEOUT_MS));
+
+        nvic.set_priority(Irq::SwTim1, 2);
+        nvic.enable_irq(Irq::SwTim1);
+
+        meter.timer.start();
+        meter
+    }
+
+    fn set_averaging(&mut self, periods: u32) {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            state.averaging = periods.max(1);
+            state.restart();
+        });
+    }
+
+    fn set_timeout(&mut self, timeout: MilliSeconds) {
+        let ticks = timeout.ticks() as u64 * self.clock as u64 / 1_000;
+        let wraps = (ticks + WRAP_TICKS - 1) / WRAP_TICKS;
+
+        interrupt::free(|cs| {
+            STATE.borrow(cs).borrow_mut().timeout_wraps = wraps.min(u32::MAX as u64) as u32;
+        });
+    }
+
+    fn average(&self) -> Result<Average, Error> {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            if state.late {
+                state.late = false;
+                return Err(Error::Overrun);
+            }
+
+            state.average.ok_or(Error::NoSignal)
+        })
+    }
+
+    fn to_micros(&self, ticks: u64) -> MicroSeconds {
+        let us = ticks * 1_000_000 / self.clock as u64;
+        MicroSeconds::from_ticks(us.min(u32::MAX as u64) as u32)
+    }
+
+    fn period(&self) -> Result<MicroSeconds, Error> {
+        self.average().map(|average| self.to_micros(average.period))
+    }
+
+    fn frequency(&self) -> Result<Hertz, Error> {
+        self.average().map(|average| {
+            let period = average.period.max(1);
+            Hertz::from_raw(((self.clock as u64 + period / 2) / period) as u32)
+        })
+    }
+
+    fn frequency_millihertz(&self) -> Result<u32, Error> {
+        self.average().map(|average| {
+            let millihertz = self.clock as u64 * 1_000 / average.period.max(1);
+            millihertz.min(u32::MAX as u64) as u32
+        })
+    }
+
+    fn free(mut self) -> (Timer1, Pin<Input<MODE>>) {
+        self.timer.stop();
+
+        let regs = &self.timer.timer;
+        regs.timer1_capture_reg.write(|w| unsafe { w.bits(0) });
+        regs.timer1_ctrl_reg
+            .modify(|_, w| w.timer1_irq_en().clear_bit());
+
+        interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = State::new());
+
+        (self.timer, self.pin)
+    }
+}
+
+/// Frequency of the rising edges on a pin
+pub struct FrequencyMeter<MODE> {
+    meter: Meter<MODE>,
+}
+
+/// Frequency, pulse widths and duty cycle of the signal on a pin
+pub struct PulseMeter<MODE> {
+    meter: Meter<MODE>,
+}
+
+impl Timer1 {
+    /// Measure the frequency on `pin` and enable the Timer1 interrupt
+    ///
+    /// The system clock gives the best resolution, the low power clock keeps
+    /// the interrupt rate low for slow signals.
+    pub fn into_frequency_meter<MODE>(
+        self,
+        pin: Pin<Input<MODE>>,
+        clocks: &Clocks,
+        clk_sel: ClockSel,
+        nvic: &mut Nvic,
+    ) -> FrequencyMeter<MODE> {
+        FrequencyMeter {
+            meter: Meter::new(self, pin, clocks, clk_sel, Mode::Frequency, nvic),
+        }
+    }
+
+    /// Measure the pulses on `pin` and enable the Timer1 interrupt
+    ///
+    /// Uses both capture inputs of Timer1.
+    pub fn into_pulse_meter<MODE>(
+        self,
+        pin: Pin<Input<MODE>>,
+        clocks: &Clocks,
+        clk_sel: ClockSel,
+        nvic: &mut Nvic,
+    ) -> PulseMeter<MODE> {
+        PulseMeter {
+            meter: Meter::new(self, pin, clocks, clk_sel, Mode::Pulse, nvic),
+        }
+    }
+}
+
+impl<MODE> FrequencyMeter<MODE> {
+    /// Average the measurement over `periods` periods of the signal
+    pub fn set_averaging(&mut self, periods: u32) {
+        self.meter.set_averaging(periods);
+    }
+
+    /// Report no signal once there was no edge for `timeout`, one second by
+    /// default
+    pub fn set_timeout(&mut self, timeout: MilliSeconds) {
+        self.meter.set_timeout(timeout);
+    }
+
+    pub fn period(&self) -> Result<MicroSeconds, Error> {
+        self.meter.period()
+    }
+
+    /// Frequency rounded to full Hertz
+    pub fn frequency(&self) -> Result<Hertz, Error> {
+        self.meter.frequency()
+    }
+
+    /// Frequency in mHz, for slow signals
+    pub fn frequency_millihertz(&self) -> Result<u32, Error> {
+        self.meter.frequency_millihertz()
+    }
+
+    /// Rising edges since the meter was started, wraps around
+    pub fn pulses(&self) -> u32 {
+        interrupt::free(|cs| STATE.borrow(cs).borrow().edges)
+    }
+
+    /// Stop measuring and release Timer1 and the pin
+    pub fn free(self) -> (Timer1, Pin<Input<MODE>>) {
+        self.meter.free()
+    }
+}
+
+impl<MODE> PulseMeter<MODE> {
+    /// Average the measurement over `periods` periods of the signal
+    ///
+    /// Does not affect [`read_pulse`](Self::read_pulse).
+    pub fn set_averaging(&mut self, periods: u32) {
+        self.meter.set_averaging(periods);
+    }
+
+    /// Report no signal once there was no edge for `timeout`, one second by
+    /// default
+    pub fn set_timeout(&mut self, timeout: MilliSeconds) {
+        self.meter.set_timeout(timeout);
+    }
+
+    pub fn period(&self) -> Result<MicroSeconds, Error> {
+        self.meter.period()
+    }
+
+    /// Frequency rounded to full Hertz
+    pub fn frequency(&self) -> Result<Hertz, Error> {
+        self.meter.frequency()
+    }
+
+    /// Frequency in mHz, for slow signals
+    pub fn frequency_millihertz(&self) -> Result<u32, Error> {
+        self.meter.frequency_millihertz()
+    }
+
+    /// Average high time
+    pub fn high_time(&self) -> Result<MicroSeconds, Error> {
+        let average = self.meter.average()?;
+        Ok(self.meter.to_micros(average.high))
+    }
+
+    /// Average low time
+    pub fn low_time(&self) -> Result<MicroSeconds, Error> {
+        let average = self.meter.average()?;
+        Ok(self.meter.to_micros(average.period - average.high))
+    }
+
+    /// Share of the high time in the period, in percent
+    pub fn duty_percent(&self) -> Result<u8, Error> {
+        let average = self.meter.average()?;
+        Ok((average.high * 100 / average.period.max(1)) as u8)
+    }
+
+    /// Take the last complete pulse, a rising edge followed by a falling and
+    /// the next rising edge
+    ///
+    /// Returns `nb::Error::WouldBlock` until a new pulse has been measured and
+    /// [`Error::Overrun`] if pulses have been lost since the last call.
+    pub fn read_pulse(&mut self) -> nb::Result<Pulse, Error> {
+        let (pulse, overrun) = interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+            let overrun = state.overrun;
+
+            state.overrun = false;
+            (state.pulse.take(), overrun)
+        });
+
+        match pulse {
+            _ if overrun => Err(nb::Error::Other(Error::Overrun)),
+            Some((high, low)) => Ok(Pulse {
+                high: self.meter.to_micros(high),
+                low: self.meter.to_micros(low),
+            }),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// Stop measuring and release Timer1 and the pin
+    pub fn free(self) -> (Timer1, Pin<Input<MODE>>) {
+        self.meter.free()
+    }
+}
+
+/// Time stamp the captured edges and count the counter wraps, called from
+/// `SWTIM1_Handler`
+pub(super) fn on_interrupt() {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if state.mode == Mode::Idle {
+            return;
+        }
+
+        let regs = regs();
+        let status = regs.timer1_status_reg.read();
+
+        let wrapped = status.timer1_timer_event().bit();
+        let rise = status.timer1_in1_event().bit();
+        let fall = status.timer1_in2_event().bit();
+
+        // More than half a wrap has passed since the counter wrapped, the
+        // captured values cannot be assigned to a wrap any more
+        let late = wrapped && status.timer1_timer_value().bits() >= (MAX_VALUE + 1) / 2;
+
+        regs.timer1_clr_event_reg.write(|w| {
+            w.timer1_clr_timer_event().bit(wrapped);
+            w.timer1_clr_in1_event().bit(rise);
+            w.timer1_clr_in2_event().bit(fall)
+        });
+
+        // A small value captured while a wrap is pending belongs to the next
+        // counter period
+        let wraps = state.wraps;
+        let stamp = |value: u16| {
+            let wraps = if wrapped && value < (MAX_VALUE + 1) / 2 {
+                wraps + 1
+            } else {
+                wraps
+            };
+
+            wraps * WRAP_TICKS + value as u64
+        };
+
+        let rise = rise.then(|| {
+            stamp(
+                regs.timer1_capcnt1_value_reg
+                    .read()
+                    .timer1_capcnt1_value()
+                    .bits(),
+            )
+        });
+        let fall = fall.then(|| {
+            stamp(
+                regs.timer1_capcnt2_value_reg
+                    .read()
+                    .timer1_capcnt2_value()
+                    .bits(),
+            )
+        });
+
+        if status.timer1_in1_ovrflw().bit() || status.timer1_in2_ovrflw().bit() {
+            // An edge has been lost, the current period cannot be measured
+            state.overrun = true;
+            state.last_rise = None;
+            state.last_fall = None;
+        }
+
+        if late {
+            state.overrun = true;
+            state.late = true;
+            state.restart();
+        }
+
+        match (rise, fall) {
+            _ if late => {}
+            (Some(rise), Some(fall)) if fall < rise => {
+                state.on_fall(fall);
+                state.on_rise(rise);
+            }
+            (rise, fall) => {
+                if let Some(rise) = rise {
+                    state.on_rise(rise);
+                }
+                if let Some(fall) = fall {
+                    state.on_fall(fall);
+                }
+            }
+        }
+
+        if wrapped {
+            state.on_wrap();
+        }
+    });
+}
@@ -0,0 +1,459 @@
+//! Monotonic time base on Timer1.
+//!
+//! Timer1 counts up at the low power clock, which keeps running in sleep as
+//! long as the timer power domain stays on. The 11 bit counter is extended to
+//! 64 bits in the interrupt: the counter restarts from zero when it reaches
+//! its reload value and every such period is added to the elapsed time.
+//!
+//! Alarms are scheduled by shortening the current period, the reload value
+//! then acts as compare value and the interrupt fires at the alarm time.
+//! A period is never shorter than [`MIN_PERIOD`] ticks, about 2 ms at
+//! 32 kHz. The interrupt has to be served within this time after a period has
+//! ended, otherwise a whole period and its ticks are lost. Alarms set less
+//! than about [`MIN_PERIOD`] ticks ahead may fire up to [`MIN_PERIOD`] ticks
+//! late, the others fire on time.
+//!
+//! With the `rtic` feature [`MonoTimer`] implements the RTIC `Monotonic`
+//! trait. With the `embassy` feature it is the `embassy-time` driver, the
+//! tick rate of `embassy-time` must match the low power clock, and the
+//! `TimerService` is not available as `embassy-time` owns the alarm.
+
+use core::cell::RefCell;
+#[cfg(not(feature = "embassy"))]
+use core::ptr;
+
+use void::Void;
+
+use crate::{
+    cm::{
+        interrupt::{self, Mutex},
+        peripheral::NVIC,
+    },
+    crg_top::Clocks,
+    nvic::{Irq, Nvic},
+    pac::{timer1::RegisterBlock, CRG_TOP, TIMER1},
+};
+
+#[cfg(not(feature = "embassy"))]
+use super::service::Service;
+use super::{ClockSel, Timer1, MAX_VALUE};
+
+/// Point in time in low power clock ticks since the timer was started
+pub type Instant<const FREQ: u32> = fugit::TimerInstantU64<FREQ>;
+
+/// Duration in low power clock ticks
+pub type Duration<const FREQ: u32> = fugit::TimerDurationU64<FREQ>;
+
+/// Minimum distance of a new reload value to the counter, so the counter
+/// cannot pass it while it is written
+const MIN_LEAD: u16 = 2;
+
+/// Shortest counter period in ticks, the worst case latency of the interrupt
+/// has to stay below it
+pub const MIN_PERIOD: u16 = 64;
+
+/// Alarm callback and its context registered by `embassy-time`
+#[cfg(feature = "embassy")]
+type Handler = (fn(*mut ()), usize);
+
+/// Timer service owning the alarm
+#[cfg(not(feature = "embassy"))]
+type Handler = &'static dyn Service;
+
+struct State {
+    running: bool,
+    /// Ticks elapsed before the current counter period
+    base: u64,
+    /// Reload value of the current counter period
+    reload: u16,
+    alarm: Option<u64>,
+    expired: bool,
+    /// Run outside of the critical section when the alarm fires
+    handler: Option<Handler>,
+    #[cfg(feature = "embassy")]
+    alarm_allocated: bool,
+}
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+impl State {
+    const fn new() -> Self {
+        Self {
+            running: false,
+            base: 0,
+            reload: MAX_VALUE,
+            alarm: None,
+            expired: false,
+            handler: None,
+            #[cfg(feature = "embassy")]
+            alarm_allocated: false,
+        }
+    }
+
+    /// Account for a counter period which has ended
+    fn sync(&mut self, regs: &RegisterBlock) -> bool {
+        let ended = regs.timer1_status_reg.read().timer1_timer_event().bit();
+
+        if ended {
+            regs.timer1_clr_event_reg
+                .write(|w| w.timer1_clr_timer_event().set_bit());
+            self.base += self.reload as u64 + 1;
+        }
+
+        ended
+    }
+
+    /// Current time in ticks
+    fn now(&mut self) -> u64 {
+        let regs = regs();
+
+        self.sync(regs);
+        let mut count = regs.timer1_status_reg.read().timer1_timer_value().bits();
+
+        // The counter may have restarted after the event was checked
+        if self.sync(regs) {
+            count = regs.timer1_status_reg.read().timer1_timer_value().bits();
+        }
+
+        self.base + count as u64
+    }
+
+    /// Choose the reload value of the current period, so it ends at the alarm
+    /// if the alarm is due within the period
+    ///
+    /// The period is never shorter than [`MIN_PERIOD`], neither is the next
+    /// one if the alarm is due early in it.
+    fn schedule(&mut self) {
+        let now = self.now();
+        let count = (now - self.base) as u16;
+        let max = MAX_VALUE as u64;
+        let min_period = MIN_PERIOD as u64;
+
+        let target = match self.alarm {
+            Some(alarm) if alarm > now => match count as u64 + (alarm - now) {
+                end if end <= max => end as u16,
+                // End this period early, so the alarm ends a period of
+                // `MIN_PERIOD` ticks
+                end if end <= max + min_period => (end - min_period) as u16,
+                _ => MAX_VALUE,
+            },
+            Some(_) => {
+                // Already due, let the interrupt handle it
+                NVIC::pend(Irq::SwTim1);
+                MAX_VALUE
+            }
+            None => MAX_VALUE,
+        };
+
+        let reload = target
+            .max(count + MIN_LEAD)
+            .clamp(MIN_PERIOD - 1, MAX_VALUE);
+
+        regs()
+            .timer1_ctrl_reg
+            .modify(|_, w| unsafe { w.timer1_reload().bits(reload) });
+        self.reload = reload;
+    }
+
+    fn set_alarm(&mut self, at: u64) {
+        self.alarm = Some(at);
+        self.expired = false;
+        self.schedule();
+    }
+
+    fn cancel_alarm(&mut self) {
+        self.alarm = None;
+        self.schedule();
+    }
+}
+
+fn regs() -> &'static RegisterBlock {
+    unsafe { &*TIMER1::ptr() }
+}
+
+/// Timer1 counting the low power clock at `FREQ` Hz
+pub struct MonoTimer<const FREQ: u32> {
+    timer: Timer1,
+}
+
+impl Timer1 {
+    /// Use Timer1 as monotonic time base and enable its interrupt
+    ///
+    /// `FREQ` is the frequency of the low power clock. The timer power domain
+    /// is kept on in sleep, so the time base keeps counting.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `FREQ` does not match the low power clock in `clocks`.
+    pub fn into_monotonic<const FREQ: u32>(
+        mut self,
+        clocks: &Clocks,
+        nvic: &mut Nvic,
+    ) -> MonoTimer<FREQ> {
+        assert_eq!(clocks.lp_clk().raw(), FREQ);
+        #[cfg(feature = "embassy")]
+        assert_eq!(embassy_time_driver::TICK_HZ, FREQ as u64);
+
+        let crg_top = unsafe { &*CRG_TOP::ptr() };
+        crg_top
+            .pmu_ctrl_reg
+            .modify(|_, w| w.tim_sleep().clear_bit());
+
+        self.stop();
+        self.enable_clock();
+        self.set_clock(ClockSel::LowPowerClock);
+        self.timer.timer1_ctrl_reg.modify(|_, w| {
+            w.timer1_count_down_en().clear_bit();
+            w.timer1_free_run_mode_en().clear_bit();
+            w.timer1_irq_en().set_bit()
+        });
+
+        let mut timer = MonoTimer { timer: self };
+        timer.restart();
+
+        nvic.set_priority(Irq::SwTim1, 2);
+        nvic.enable_irq(Irq::SwTim1);
+
+        timer
+    }
+}
+
+impl<const FREQ: u32> MonoTimer<FREQ> {
+    pub fn now(&self) -> Instant<FREQ> {
+        Instant::from_ticks(self.ticks())
+    }
+
+    /// Ticks since the timer was started
+    pub fn ticks(&self) -> u64 {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().now())
+    }
+
+    /// Raise the Timer1 interrupt at `at`, replacing a pending alarm
+    ///
+    /// An alarm in the past fires right away.
+    pub fn set_alarm(&mut self, at: Instant<FREQ>) {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().set_alarm(at.ticks()));
+    }
+
+    pub fn cancel_alarm(&mut self) {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().cancel_alarm());
+    }
+
+    /// Check whether the alarm has fired since the last call
+    ///
+    /// Returns `nb::Error::WouldBlock` until then.
+    pub fn wait_alarm(&mut self) -> nb::Result<(), Void> {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            if state.expired {
+                state.expired = false;
+                Ok(())
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        })
+    }
+
+    /// Stop the timer and release Timer1
+    pub fn free(mut self) -> Timer1 {
+        self.timer.stop();
+        self.timer
+            .timer
+            .timer1_ctrl_reg
+            .modify(|_, w| w.timer1_irq_en().clear_bit());
+
+        interrupt::free(|cs| *STATE.borrow(cs).borrow_mut() = State::new());
+
+        self.timer
+    }
+
+    /// Restart counting from zero, without any alarm
+    fn restart(&mut self) {
+        self.timer.stop();
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            regs()
+                .timer1_clr_event_reg
+                .write(|w| w.timer1_clr_timer_event().set_bit());
+            state.running = true;
+            state.base = 0;
+            state.alarm = None;
+            state.expired = false;
+            state.schedule();
+        });
+
+        self.timer.start();
+    }
+}
+
+#[cfg(feature = "rtic")]
+impl<const FREQ: u32> rtic_monotonic::Monotonic for MonoTimer<FREQ> {
+    // The interrupt also extends the counter, it must stay enabled
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+    type Instant = Instant<FREQ>;
+    type Duration = Duration<FREQ>;
+
+    fn now(&mut self) -> Self::Instant {
+        MonoTimer::now(self)
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        self.set_alarm(instant);
+    }
+
+    fn clear_compare_flag(&mut self) {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().expired = false);
+    }
+
+    fn zero() -> Self::Instant {
+        Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.restart();
+    }
+
+    fn on_interrupt(&mut self) {
+        super::on_interrupt();
+    }
+}
+
+#[cfg(feature = "embassy")]
+mod embassy {
+    use embassy_time_driver::{AlarmHandle, Driver};
+
+    use super::{interrupt, STATE};
+
+    /// `embassy-time` driver with a single alarm
+    struct TimeDriver;
+
+    impl Driver for TimeDriver {
+        fn now(&self) -> u64 {
+            interrupt::free(|cs| STATE.borrow(cs).borrow_mut().now())
+        }
+
+        unsafe fn allocate_alarm(&self) -> Option<AlarmHandle> {
+            interrupt::free(|cs| {
+                let mut state = STATE.borrow(cs).borrow_mut();
+
+                if state.alarm_allocated {
+                    None
+                } else {
+                    state.alarm_allocated = true;
+                    Some(AlarmHandle::new(0))
+                }
+            })
+        }
+
+        fn set_alarm_callback(&self, _alarm: AlarmHandle, callback: fn(*mut ()), ctx: *mut ()) {
+            interrupt::free(|cs| {
+                STATE.borrow(cs).borrow_mut().handler = Some((callback, ctx as usize));
+            });
+        }
+
+        fn set_alarm(&self, _alarm: AlarmHandle, timestamp: u64) -> bool {
+            interrupt::free(|cs| {
+                let mut state = STATE.borrow(cs).borrow_mut();
+
+                if timestamp <= state.now() {
+                    state.cancel_alarm();
+                    false
+                } else {
+                    state.set_alarm(timestamp);
+                    true
+                }
+            })
+        }
+    }
+
+    embassy_time_driver::time_driver_impl!(static DRIVER: TimeDriver = TimeDriver);
+}
+
+/// Current time in ticks
+#[cfg(not(feature = "embassy"))]
+pub(super) fn now() -> u64 {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().now())
+}
+
+/// Set the alarm for `service`, or cancel it if `at` is `None`
+///
+/// Ignored unless `service` is attached.
+#[cfg(not(feature = "embassy"))]
+pub(super) fn set_alarm(service: &dyn Service, at: Option<u64>) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        let attached = state.handler.map_or(false, |handler| {
+            ptr::eq(
+                handler as *const dyn Service as *const u8,
+                service as *const dyn Service as *const u8,
+            )
+        });
+        if !attached {
+            return;
+        }
+
+        match at {
+            Some(at) => state.set_alarm(at),
+            None => state.cancel_alarm(),
+        }
+    });
+}
+
+/// Run `service` whenever the alarm fires, or stop running it
+#[cfg(not(feature = "embassy"))]
+pub(super) fn attach(service: Option<&'static dyn Service>) {
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        state.handler = service;
+        state.cancel_alarm();
+    });
+}
+
+#[cfg(feature = "embassy")]
+fn run((callback, ctx): Handler) {
+    callback(ctx as *mut ());
+}
+
+#[cfg(not(feature = "embassy"))]
+fn run(service: Handler) {
+    service.on_alarm();
+}
+
+/// Extend the counter and fire a due alarm, called from `SWTIM1_Handler`
+pub(super) fn on_interrupt() {
+    let handler = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if !state.running {
+            return None;
+        }
+
+        let now = state.now();
+
+        let fired = matches!(state.alarm, Some(alarm) if alarm <= now);
+        if fired {
+            state.alarm = None;
+            state.expired = true;
+        }
+
+        state.schedule();
+
+        if fired {
+            state.handler
+        } else {
+            None
+        }
+    });
+
+    // Called outside of the critical section, the handler may set the next
+    // alarm
+    if let Some(handler) = handler {
+        run(handler);
+    }
+}
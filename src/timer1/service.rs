@@ -0,0 +1,379 @@
+//! Software timers multiplexed onto the Timer1 alarm.
+//!
+//! A [`TimerService`] holds up to `N` one-shot or periodic timers in a queue
+//! sorted by deadline. Once the [`MonoTimer`] is attached, its alarm is always
+//! set to the nearest deadline and expired timers run their callback from the
+//! Timer1 interrupt.
+//!
+//! The number of timers is fixed by `N` on purpose instead of being
+//! unlimited: the queue is a plain array without any allocator, and starting
+//! a timer beyond `N` fails with [`Error::Full`].
+//!
+//! The service is meant to live in a `static`. It keeps its queue behind a
+//! critical section, so timers can be started and cancelled from the main
+//! loop as well as from interrupt handlers such as `SWTIM_Handler`.
+//!
+//! The service is not available with the `embassy` feature, as
+//! `embassy-time` owns the alarm. With the `rtic` feature the [`MonoTimer`]
+//! is handed either to RTIC or to the service.
+
+use core::cell::RefCell;
+
+use crate::{
+    cm::interrupt::{self, Mutex},
+    time::MicroSeconds,
+};
+
+use super::{
+    monotonic::{self, Instant},
+    MonoTimer,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// All `N` timers are in use
+    Full,
+    /// The timer has expired or has been cancelled
+    NotActive,
+}
+
+/// Handle of a started timer
+///
+/// A handle becomes stale once its timer has expired or has been cancelled,
+/// it never refers to a timer started later in the same slot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimerId {
+    slot: u8,
+    generation: u8,
+}
+
+/// Callback of an expired timer, with the context given when the timer was
+/// started
+pub type Callback = fn(TimerId, usize);
+
+#[derive(Clone, Copy)]
+struct Entry {
+    deadline: u64,
+    /// Period in ticks of a periodic timer
+    period: Option<u64>,
+    callback: Callback,
+    context: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    entry: Option<Entry>,
+    generation: u8,
+}
+
+struct Queue<const N: usize> {
+    slots: [Slot; N],
+    /// Indices of the used slots, ordered by deadline
+    order: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Queue<N> {
+    const fn new() -> Self {
+        Self {
+            slots: [Slot {
+                entry: None,
+                generation: 0,
+            }; N],
+            order: [0; N],
+            len: 0,
+        }
+    }
+
+    fn deadline(&self, slot: u8) -> u64 {
+        self.slots[slot as usize]
+            .entry
+            .map(|entry| entry.deadline)
+            .unwrap_or(u64::MAX)
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.order[..self.len]
+            .first()
+            .map(|&slot| self.deadline(slot))
+    }
+
+    fn entry(&self, id: TimerId) -> Option<&Entry> {
+        let slot = self.slots.get(id.slot as usize)?;
+
+        if slot.generation == id.generation {
+            slot.entry.as_ref()
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<TimerId, Error> {
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| slot.entry.is_none())
+            .ok_or(Error::Full)? as u8;
+
+        self.slots[slot as usize].entry = Some(entry);
+        self.enqueue(slot);
+
+        Ok(TimerId {
+            slot,
+            generation: self.slots[slot as usize].generation,
+        })
+    }
+
+    fn remove(&mut self, id: TimerId) -> Result<(), Error> {
+        if self.entry(id).is_none() {
+            return Err(Error::NotActive);
+        }
+
+        self.dequeue(id.slot);
+        self.release(id.slot);
+
+        Ok(())
+    }
+
+    /// Take the first timer which has expired at `now`, a periodic timer is
+    /// queued again for its next deadline
+    fn pop_expired(&mut self, now: u64) -> Option<(TimerId, Callback, usize)> {
+        let slot = *self.order[..self.len].first()?;
+        let entry = self.slots[slot as usize].entry?;
+
+        if entry.deadline > now {
+            return None;
+        }
+
+        let id = TimerId {
+            slot,
+            generation: self.slots[slot as usize].generation,
+        };
+
+        self.dequeue(slot);
+
+        match entry.period {
+            Some(period) => {
+                // Skip the periods which have been missed, but keep the phase
+                let missed = (now - entry.deadline) / period;
+                let deadline = entry.deadline + (missed + 1) * period;
+
+                self.slots[slot as usize].entry = Some(Entry { deadline, ..entry });
+                self.enqueue(slot);
+            }
+            None => self.release(slot),
+        }
+
+        Some((id, entry.callback, entry.context))
+    }
+
+    /// Insert a used slot into the order, after timers with the same deadline
+    fn enqueue(&mut self, slot: u8) {
+        let deadline = self.deadline(slot);
+        let position = self.order[..self.len]
+            .iter()
+            .position(|&other| self.deadline(other) > deadline)
+            .unwrap_or(self.len);
+
+        self.order.copy_within(position..self.len, position + 1);
+        self.order[position] = slot;
+        self.len += 1;
+    }
+
+    fn dequeue(&mut self, slot: u8) {
+        if let Some(position) = self.order[..self.len]
+            .iter()
+            .position(|&other| other == slot)
+        {
+            self.order.copy_within(position + 1..self.len, position);
+            self.len -= 1;
+        }
+    }
+
+    fn release(&mut self, slot: u8) {
+        let slot = &mut self.slots[slot as usize];
+
+        slot.entry = None;
+        slot.generation = slot.generation.wrapping_add(1);
+    }
+}
+
+/// Up to `N` software timers on the monotonic time base counting at `FREQ` Hz
+pub struct TimerService<const FREQ: u32, const N: usize> {
+    queue: Mutex<RefCell<Queue<N>>>,
+}
+
+impl<const FREQ: u32, const N: usize> TimerService<FREQ, N> {
+    /// # Panics
+    ///
+    /// Panics if `N` is larger than 256.
+    pub const fn new() -> Self {
+        assert!(N <= 256);
+
+        Self {
+            queue: Mutex::new(RefCell::new(Queue::new())),
+        }
+    }
+
+    /// Run `callback` once after `timeout`
+    ///
+    /// `context` is passed to the callback, e.g. an index into a table of the
+    /// application.
+    pub fn start_one_shot(
+        &self,
+        timeout: MicroSeconds,
+        callback: Callback,
+        context: usize,
+    ) -> Result<TimerId, Error> {
+        self.start(timeout, None, callback, context)
+    }
+
+    /// Run `callback` every `period`, the first time after one period
+    ///
+    /// `context` is passed to the callback on every expiry.
+    pub fn start_periodic(
+        &self,
+        period: MicroSeconds,
+        callback: Callback,
+        context: usize,
+    ) -> Result<TimerId, Error> {
+        let ticks = Self::ticks(period);
+        self.start(period, Some(ticks), callback, context)
+    }
+
+    /// Stop a timer before it expires
+    pub fn cancel(&self, id: TimerId) -> Result<(), Error> {
+        interrupt::free(|cs| {
+            let mut queue = self.queue.borrow(cs).borrow_mut();
+
+            queue.remove(id)?;
+            monotonic::set_alarm(self, queue.next_deadline());
+
+            Ok(())
+        })
+    }
+
+    /// Check whether a timer is still pending
+    pub fn is_active(&self, id: TimerId) -> bool {
+        interrupt::free(|cs| self.queue.borrow(cs).borrow().entry(id).is_some())
+    }
+
+    /// Number of pending timers
+    pub fn len(&self) -> usize {
+        interrupt::free(|cs| self.queue.borrow(cs).borrow().len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn start(
+        &self,
+        timeout: MicroSeconds,
+        period: Option<u64>,
+        callback: Callback,
+        context: usize,
+    ) -> Result<TimerId, Error> {
+        let deadline = monotonic::now() + Self::ticks(timeout);
+
+        interrupt::free(|cs| {
+            let mut queue = self.queue.borrow(cs).borrow_mut();
+
+            let id = queue.insert(Entry {
+                deadline,
+                period,
+                callback,
+                context,
+            })?;
+            monotonic::set_alarm(self, queue.next_deadline());
+
+            Ok(id)
+        })
+    }
+
+    /// Duration in ticks, rounded up to at least one tick
+    fn ticks(duration: MicroSeconds) -> u64 {
+        ((duration.ticks() as u64 * FREQ as u64 + 999_999) / 1_000_000).max(1)
+    }
+}
+
+impl<const FREQ: u32, const N: usize> Default for TimerService<FREQ, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run by the monotonic timer when its alarm fires
+pub(super) trait Service: Sync {
+    fn on_alarm(&self);
+}
+
+impl<const FREQ: u32, const N: usize> Service for TimerService<FREQ, N> {
+    fn on_alarm(&self) {
+        loop {
+            let expired = interrupt::free(|cs| {
+                let mut queue = self.queue.borrow(cs).borrow_mut();
+                let expired = queue.pop_expired(monotonic::now());
+
+                if expired.is_none() {
+                    monotonic::set_alarm(self, queue.next_deadline());
+                }
+
+                expired
+            });
+
+            // Called outside of the critical section, the callback may start
+            // or cancel timers
+            match expired {
+                Some((id, callback, context)) => callback(id, context),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Monotonic timer whose alarm is owned by a timer service
+///
+/// Created by [`MonoTimer::attach`], the time can still be read.
+pub struct ServiceTimer<const FREQ: u32> {
+    timer: MonoTimer<FREQ>,
+}
+
+impl<const FREQ: u32> MonoTimer<FREQ> {
+    /// Hand the alarm over to a timer service
+    ///
+    /// The timer is consumed, so the alarm cannot be set directly or by RTIC
+    /// any more. A pending alarm is cancelled.
+    pub fn attach<const N: usize>(
+        self,
+        service: &'static TimerService<FREQ, N>,
+    ) -> ServiceTimer<FREQ> {
+        monotonic::attach(Some(service));
+
+        interrupt::free(|cs| {
+            let queue = service.queue.borrow(cs).borrow();
+            monotonic::set_alarm(service, queue.next_deadline());
+        });
+
+        ServiceTimer { timer: self }
+    }
+}
+
+impl<const FREQ: u32> ServiceTimer<FREQ> {
+    pub fn now(&self) -> Instant<FREQ> {
+        self.timer.now()
+    }
+
+    /// Ticks since the timer was started
+    pub fn ticks(&self) -> u64 {
+        self.timer.ticks()
+    }
+
+    /// Take the alarm back from the timer service
+    ///
+    /// The timers of the service stay queued, but no longer expire.
+    pub fn detach(self) -> MonoTimer<FREQ> {
+        monotonic::attach(None);
+        self.timer
+    }
+}